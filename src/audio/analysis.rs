@@ -0,0 +1,276 @@
+//! Real-time analysis of playing audio, for music-reactive visuals.
+
+use bevy::prelude::*;
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use dasp_sample::Sample;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
+use super::control::AtomicF32;
+
+/// The length of an analysis window, in frames.
+pub const ANALYSIS_WINDOW: usize = 1024;
+
+/// How many times [`AudioAnalysis`] is read before giving up on a frame
+/// that keeps being written.
+const READ_ATTEMPTS: usize = 4;
+
+/// The latest analysis of some audio.
+///
+/// Insert this as a resource to analyze the master bus, or as a component
+/// next to an [`AudioControl`](super::AudioControl) to analyze that voice.
+/// It is updated every frame, before [`Update`]:
+/// ```ignore
+/// fn pulse_background(analysis: Res<AudioAnalysis>, mut bg: Query<&mut Sprite, With<Background>>) {
+///     let bass = analysis.band(20., 150.);
+///     // ...
+/// }
+/// ```
+/// Voices are analyzed before their volume and bus gain, so visuals don't
+/// change with the volume settings. The master bus is analyzed as it is
+/// heard.
+#[derive(Clone, Component, Debug, Default, Resource)]
+pub struct AudioAnalysis {
+    /// The root mean square level of the window, where `1.0` is full scale.
+    pub rms: f32,
+    /// The peak level of the window, where `1.0` is full scale.
+    pub peak: f32,
+    /// The magnitude of each frequency bin, where a full scale sine is
+    /// `1.0`.
+    ///
+    /// This is empty until the first window is analyzed.
+    pub spectrum: Vec<f32>,
+    /// The width of each bin of [`AudioAnalysis::spectrum`], in Hz.
+    pub bin_width: f32,
+}
+
+impl AudioAnalysis {
+    /// Returns the average magnitude of the spectrum from `low` to `high`
+    /// Hz.
+    pub fn band(&self, low: f32, high: f32) -> f32 {
+        if self.spectrum.is_empty() || self.bin_width <= 0. {
+            return 0.;
+        }
+
+        let last = self.spectrum.len() - 1;
+        let low = ((low / self.bin_width) as usize).min(last);
+        let high = ((high / self.bin_width) as usize).clamp(low, last);
+
+        let bins = &self.spectrum[low..=high];
+
+        bins.iter().sum::<f32>() / bins.len() as f32
+    }
+}
+
+/// An analysis point on the audio thread, shared with the game.
+///
+/// The analysis itself is done on the audio thread and published with a
+/// sequence lock, so neither side ever waits on the other.
+pub(super) struct AnalysisTap {
+    /// Only locked by the game to turn the tap on or off; the audio thread
+    /// skips the analysis if it can't get it.
+    analyzer: Mutex<Option<Analyzer>>,
+    enabled: AtomicBool,
+    /// The sample rate the analyzer was made for.
+    sample_rate: AtomicU32,
+    /// Only locked by the game, to read a frame before it is validated.
+    scratch: Mutex<Vec<f32>>,
+
+    /// Odd while a frame is being written.
+    sequence: AtomicU64,
+    rms: AtomicF32,
+    peak: AtomicF32,
+    spectrum: Box<[AtomicF32]>,
+    bin_width: AtomicF32,
+}
+
+impl AnalysisTap {
+    /// Creates a new, disabled `AnalysisTap`.
+    pub fn new() -> AnalysisTap {
+        AnalysisTap {
+            analyzer: Mutex::new(None),
+            enabled: AtomicBool::new(false),
+            sample_rate: AtomicU32::new(0),
+            scratch: Mutex::new(Vec::new()),
+            sequence: AtomicU64::new(0),
+            rms: AtomicF32::new(0.),
+            peak: AtomicF32::new(0.),
+            spectrum: (0..ANALYSIS_WINDOW / 2)
+                .map(|_| AtomicF32::new(0.))
+                .collect(),
+            bin_width: AtomicF32::new(0.),
+        }
+    }
+
+    /// Turns the tap on or off.
+    ///
+    /// The analyzer is made again if `sample_rate` changed since it was
+    /// turned on, like after the device is changed.
+    ///
+    /// This allocates, so it must not be called from the audio thread.
+    pub fn set_enabled(&self, enabled: bool, sample_rate: u32) {
+        if self.enabled.load(Ordering::Acquire) == enabled
+            && (!enabled || self.sample_rate.load(Ordering::Acquire) == sample_rate)
+        {
+            return;
+        }
+
+        let analyzer = enabled.then(|| Analyzer::new(sample_rate));
+
+        if let Ok(mut slot) = self.analyzer.lock() {
+            *slot = analyzer;
+            self.sample_rate.store(sample_rate, Ordering::Release);
+            self.enabled.store(enabled, Ordering::Release);
+        }
+    }
+
+    /// Analyzes interleaved `samples` with `channels` channels.
+    ///
+    /// This never blocks, and does nothing while the tap is off.
+    pub fn process(&self, samples: &[i16], channels: usize) {
+        if !self.enabled.load(Ordering::Acquire) {
+            return;
+        }
+
+        if let Ok(mut analyzer) = self.analyzer.try_lock() {
+            if let Some(analyzer) = analyzer.as_mut() {
+                analyzer.process(samples, channels, self);
+            }
+        }
+    }
+
+    /// Reads the latest frame into `analysis`.
+    ///
+    /// If a frame is being written the whole time, `analysis` is left as it
+    /// was.
+    pub fn read(&self, analysis: &mut AudioAnalysis) {
+        let Ok(mut spectrum) = self.scratch.lock() else {
+            return;
+        };
+
+        for _ in 0..READ_ATTEMPTS {
+            let before = self.sequence.load(Ordering::Acquire);
+
+            if before == 0 {
+                // nothing published yet
+                return;
+            }
+
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+
+            let rms = self.rms.load();
+            let peak = self.peak.load();
+            let bin_width = self.bin_width.load();
+
+            spectrum.clear();
+            spectrum.extend(self.spectrum.iter().map(AtomicF32::load));
+
+            if self.sequence.load(Ordering::Acquire) == before {
+                analysis.rms = rms;
+                analysis.peak = peak;
+                analysis.bin_width = bin_width;
+                analysis.spectrum.clone_from(&spectrum);
+                return;
+            }
+        }
+    }
+
+    fn publish(&self, rms: f32, peak: f32, spectrum: &[f32], bin_width: f32) {
+        self.sequence.fetch_add(1, Ordering::AcqRel);
+
+        self.rms.store(rms);
+        self.peak.store(peak);
+        self.bin_width.store(bin_width);
+
+        for (bin, magnitude) in self.spectrum.iter().zip(spectrum) {
+            bin.store(*magnitude);
+        }
+
+        self.sequence.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+/// The buffers and FFT plan of an [`AnalysisTap`].
+struct Analyzer {
+    fft: Arc<dyn Fft<f32>>,
+    /// Mono samples of the current window.
+    window: Vec<f32>,
+    /// The Hann window function.
+    hann: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    spectrum: Vec<f32>,
+    bin_width: f32,
+}
+
+impl Analyzer {
+    fn new(sample_rate: u32) -> Analyzer {
+        let fft = FftPlanner::new().plan_fft_forward(ANALYSIS_WINDOW);
+        let scratch = vec![Complex::default(); fft.get_inplace_scratch_len()];
+
+        let hann = (0..ANALYSIS_WINDOW)
+            .map(|i| {
+                let phase = std::f32::consts::TAU * i as f32 / ANALYSIS_WINDOW as f32;
+                0.5 - 0.5 * phase.cos()
+            })
+            .collect();
+
+        Analyzer {
+            fft,
+            window: Vec::with_capacity(ANALYSIS_WINDOW),
+            hann,
+            buffer: vec![Complex::default(); ANALYSIS_WINDOW],
+            scratch,
+            spectrum: vec![0.; ANALYSIS_WINDOW / 2],
+            bin_width: sample_rate as f32 / ANALYSIS_WINDOW as f32,
+        }
+    }
+
+    fn process(&mut self, samples: &[i16], channels: usize, tap: &AnalysisTap) {
+        for frame in samples.chunks_exact(channels) {
+            let sum = frame.iter().map(|s| s.to_sample::<f32>()).sum::<f32>();
+            self.window.push(sum / channels as f32);
+
+            if self.window.len() == ANALYSIS_WINDOW {
+                self.analyze(tap);
+
+                // windows overlap by half, so updates come twice as often
+                self.window.drain(..ANALYSIS_WINDOW / 2);
+            }
+        }
+    }
+
+    fn analyze(&mut self, tap: &AnalysisTap) {
+        let mut square_sum = 0.;
+        let mut peak = 0f32;
+
+        for ((sample, hann), out) in self.window.iter().zip(&self.hann).zip(&mut self.buffer) {
+            square_sum += sample * sample;
+            peak = peak.max(sample.abs());
+
+            *out = Complex::new(sample * hann, 0.);
+        }
+
+        let rms = (square_sum / ANALYSIS_WINDOW as f32).sqrt();
+
+        self.fft
+            .process_with_scratch(&mut self.buffer, &mut self.scratch);
+
+        // the hann window halves the amplitude, and the other half of the
+        // energy is in the mirrored bins
+        let scale = 4. / ANALYSIS_WINDOW as f32;
+
+        for (magnitude, bin) in self.spectrum.iter_mut().zip(&self.buffer) {
+            *magnitude = bin.norm() * scale;
+        }
+
+        tap.publish(rms, peak, &self.spectrum, self.bin_width);
+    }
+}
@@ -5,46 +5,582 @@ use bevy::prelude::*;
 use bevy::utils::BoxedFuture;
 
 use std::io::Cursor;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+use std::time::Duration;
 
-use lewton::header::{CommentHeader, IdentHeader, SetupHeader};
+use claxon::FlacReader;
+
+use lewton::header::{CommentHeader, IdentHeader};
 use lewton::inside_ogg::OggStreamReader;
 
+use serde::{Deserialize, Serialize};
+
+use super::loudness::Loudness;
+use super::source::{
+    ChannelConverter, DecodeError, DecodedSource, Decoder, Resampler, Source, WavDecoder,
+};
+use super::waveform::Waveform;
+use super::{CHANNEL_COUNT, DEFAULT_SAMPLE_RATE};
+
+/// The label of the [`DecodedSound`] of a file loaded with
+/// [`AudioLoadMode::Decoded`].
+pub const DECODED_LABEL: &str = "decoded";
+
+/// The label of the [`Waveform`] of a file loaded with
+/// [`AudioLoaderSettings::waveform`].
+pub const WAVEFORM_LABEL: &str = "waveform";
+
+/// How many samples are decoded at once when loading a [`DecodedSound`].
+const DECODE_BUFFER_LEN: usize = 4096;
+
 /// Loads files as [`AudioSource`] [`Assets`](bevy::asset::Assets).
 #[derive(Default)]
-pub struct AudioLoader;
+pub struct AudioLoader {
+    output_rate: OutputRate,
+}
+
+impl AudioLoader {
+    /// Creates a new `AudioLoader` that decodes sounds at `output_rate`.
+    pub(super) fn new(output_rate: OutputRate) -> AudioLoader {
+        AudioLoader { output_rate }
+    }
+}
+
+/// Settings for the [`AudioLoader`].
+///
+/// To load a [`DecodedSound`], load the [`DECODED_LABEL`] of a file with
+/// [`AudioLoadMode::Decoded`]:
+///
+/// ```ignore
+/// let hitsound: Handle<DecodedSound> = asset_server.load_with_settings(
+///     "sounds/hit.ogg#decoded",
+///     |settings: &mut AudioLoaderSettings| settings.mode = AudioLoadMode::Decoded,
+/// );
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AudioLoaderSettings {
+    /// How the file is loaded.
+    pub mode: AudioLoadMode,
+    /// The loop region of the file.
+    ///
+    /// If this is `None`, the loop region is read from the `LOOPSTART` and
+    /// `LOOPLENGTH` tags of the file, like RPG Maker does.
+    #[serde(default)]
+    pub loop_region: Option<LoopRegion>,
+    /// Whether a [`Waveform`] of the file is also generated.
+    #[serde(default)]
+    pub waveform: bool,
+    /// How the volume of the file is normalized.
+    #[serde(default)]
+    pub normalization: Normalization,
+}
+
+/// How the [`AudioLoader`] normalizes the volume of a file.
+///
+/// The result is stored in [`AudioSource::gain`], which playback applies on
+/// top of the volume of the [`AudioControl`](super::AudioControl).
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub enum Normalization {
+    /// The file plays as it is.
+    #[default]
+    Off,
+    /// The `REPLAYGAIN_TRACK_GAIN` and `REPLAYGAIN_TRACK_PEAK` tags are
+    /// used.
+    ///
+    /// Files without them are measured like [`Normalization::Loudness`].
+    ReplayGain,
+    /// The integrated loudness of the file is measured when it is loaded,
+    /// which decodes all of it.
+    ///
+    /// See [`Loudness::measure`].
+    Loudness,
+}
+
+/// How the [`AudioLoader`] loads a file.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub enum AudioLoadMode {
+    /// The file is kept encoded and decoded as it plays.
+    ///
+    /// This is best for long audio, like songs.
+    #[default]
+    Streamed,
+    /// The file is also decoded into a [`DecodedSound`] when it is loaded.
+    ///
+    /// This is best for short sounds played often, like hitsounds.
+    Decoded,
+}
 
 impl AssetLoader for AudioLoader {
     type Asset = AudioSource;
-    type Settings = ();
+    type Settings = AudioLoaderSettings;
     type Error = std::io::Error;
 
     fn load<'a>(
         &'a self,
         reader: &'a mut Reader,
-        _settings: &'a Self::Settings,
-        _load_context: &'a mut LoadContext,
+        settings: &'a Self::Settings,
+        load_context: &'a mut LoadContext,
     ) -> BoxedFuture<'a, Result<AudioSource, Self::Error>> {
         Box::pin(async move {
             let mut bytes = Vec::new();
             reader.read_to_end(&mut bytes).await?;
-            Ok(AudioSource {
+
+            // the container is found from the file itself, not its extension
+            let format = AudioFormat::detect(&bytes).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "unrecognized audio container",
+                )
+            })?;
+
+            let mut source = AudioSource {
                 bytes: bytes.into(),
-            })
+                format,
+                metadata: AudioMetadata::default(),
+                loop_region: None,
+                gain: 1.,
+            };
+
+            // a file without valid headers won't play either
+            source.metadata = AudioMetadata::read(&source).map_err(invalid_data)?;
+
+            source.loop_region = settings
+                .loop_region
+                .or_else(|| tag_loop_region(&source.metadata));
+
+            source.gain = match settings.normalization {
+                Normalization::Off => 1.,
+                Normalization::ReplayGain => match tag_replay_gain(&source.metadata) {
+                    Some(gain) => gain,
+                    None => measure_gain(&source)?,
+                },
+                Normalization::Loudness => measure_gain(&source)?,
+            };
+
+            if settings.mode == AudioLoadMode::Decoded {
+                let sound = DecodedSound::decode(source.clone(), self.output_rate.get())?;
+                load_context.add_labeled_asset(String::from(DECODED_LABEL), sound);
+            }
+
+            if settings.waveform {
+                let waveform = Waveform::generate(source.clone()).map_err(invalid_data)?;
+                load_context.add_labeled_asset(String::from(WAVEFORM_LABEL), waveform);
+            }
+
+            Ok(source)
         })
     }
 
     fn extensions(&self) -> &[&str] {
-        &["ogg"]
+        &["ogg", "wav", "flac"]
     }
 }
 
 /// A single audio asset.
 ///
-/// This is in the OGG format.
+/// This holds the encoded file; see [`AudioSource::format`] for what
+/// container it is in.
 #[derive(Asset, Debug, Clone, TypePath)]
 pub struct AudioSource {
     pub bytes: Arc<[u8]>,
+    /// The container of the audio.
+    pub format: AudioFormat,
+    /// The metadata of the file, read when it was loaded.
+    pub metadata: AudioMetadata,
+    /// The region of the audio that loops, if any.
+    pub loop_region: Option<LoopRegion>,
+    /// The linear gain that normalizes the volume of the audio, applied
+    /// automatically when it plays.
+    ///
+    /// This is `1.0` unless the file was loaded with a [`Normalization`].
+    pub gain: f32,
+}
+
+/// The metadata of an [`AudioSource`].
+///
+/// This is read from the headers of the file when it is loaded, so it is
+/// available without decoding anything. Tags are the Vorbis comments of
+/// `.ogg` and `.flac` files; `.wav` files have none.
+#[derive(Clone, Debug, Default)]
+pub struct AudioMetadata {
+    /// The `TITLE` tag.
+    pub title: Option<String>,
+    /// The `ARTIST` tag.
+    pub artist: Option<String>,
+    /// The `ALBUM` tag.
+    pub album: Option<String>,
+    /// The length of the audio, if the file records it.
+    pub duration: Option<Duration>,
+    /// The sample rate of the file.
+    pub sample_rate: u32,
+    /// The channel count of the file.
+    pub channels: u8,
+    /// Every tag of the file, in order.
+    pub tags: Vec<(String, String)>,
+}
+
+impl AudioMetadata {
+    /// Reads the metadata of `source`.
+    pub fn read(source: &AudioSource) -> Result<AudioMetadata, DecodeError> {
+        match source.format {
+            AudioFormat::Ogg => {
+                let stream = OggStreamReader::new(Cursor::new(source.clone()))?;
+                let frames = last_granule_position(&source.bytes);
+
+                Ok(AudioMetadata::from_vorbis(
+                    &stream.ident_hdr,
+                    &stream.comment_hdr,
+                    frames,
+                ))
+            }
+            AudioFormat::Flac => {
+                let reader = FlacReader::new(Cursor::new(source.clone()))?;
+                let streaminfo = reader.streaminfo();
+                let tags = reader
+                    .tags()
+                    .map(|(k, v)| (k.to_owned(), v.to_owned()))
+                    .collect();
+
+                Ok(AudioMetadata::new(
+                    streaminfo.sample_rate,
+                    streaminfo.channels as u8,
+                    streaminfo.samples,
+                    tags,
+                ))
+            }
+            AudioFormat::Wav => {
+                let decoder = WavDecoder::new(source.clone())?;
+
+                Ok(AudioMetadata::new(
+                    decoder.sample_rate(),
+                    decoder.channels(),
+                    Some(decoder.frames()),
+                    Vec::new(),
+                ))
+            }
+        }
+    }
+
+    /// Creates metadata from the headers of a Vorbis stream.
+    ///
+    /// `frames` is the length of the stream, if known.
+    pub fn from_vorbis(
+        ident: &IdentHeader,
+        comments: &CommentHeader,
+        frames: Option<u64>,
+    ) -> AudioMetadata {
+        AudioMetadata::new(
+            ident.audio_sample_rate,
+            ident.audio_channels,
+            frames,
+            comments.comment_list.clone(),
+        )
+    }
+
+    fn new(
+        sample_rate: u32,
+        channels: u8,
+        frames: Option<u64>,
+        tags: Vec<(String, String)>,
+    ) -> AudioMetadata {
+        let mut metadata = AudioMetadata {
+            title: None,
+            artist: None,
+            album: None,
+            duration: frames
+                .filter(|_| sample_rate > 0)
+                .map(|frames| Duration::from_secs_f64(frames as f64 / sample_rate as f64)),
+            sample_rate,
+            channels,
+            tags,
+        };
+
+        metadata.title = metadata.tag("TITLE").map(String::from);
+        metadata.artist = metadata.tag("ARTIST").map(String::from);
+        metadata.album = metadata.tag("ALBUM").map(String::from);
+
+        metadata
+    }
+
+    /// Returns the first tag named `key`, which is case-insensitive.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.trim())
+    }
+}
+
+/// Finds the granule position of the last Ogg page, which for Vorbis is the
+/// length of the stream in frames.
+fn last_granule_position(bytes: &[u8]) -> Option<u64> {
+    let mut end = bytes.len();
+
+    // the capture pattern can also turn up inside packet data
+    let page = loop {
+        let page = bytes[..end]
+            .windows(4)
+            .rposition(|capture| capture == b"OggS")?;
+
+        if is_last_ogg_page(bytes, page) {
+            break page;
+        }
+
+        end = page + 3;
+    };
+
+    let granule = bytes.get((page + 6)..(page + 14))?;
+    let granule = u64::from_le_bytes(granule.try_into().ok()?);
+
+    // pages where no packet ends have no position
+    (granule != u64::MAX).then_some(granule)
+}
+
+/// Checks if a real Ogg page starts at `page`, and it is the last one: it
+/// ends the stream, or it runs to the end of the file.
+fn is_last_ogg_page(bytes: &[u8], page: usize) -> bool {
+    const VERSION: usize = 4;
+    const HEADER_TYPE: usize = 5;
+    const SEGMENTS: usize = 26;
+    const END_OF_STREAM: u8 = 0x04;
+
+    let Some(&segments) = bytes.get(page + SEGMENTS) else {
+        return false;
+    };
+
+    let table = (page + SEGMENTS + 1)..(page + SEGMENTS + 1 + segments as usize);
+
+    let Some(lacing) = bytes.get(table.clone()) else {
+        return false;
+    };
+
+    let page_end = table.end + lacing.iter().map(|l| *l as usize).sum::<usize>();
+
+    bytes[page + VERSION] == 0
+        && page_end <= bytes.len()
+        && (bytes[page + HEADER_TYPE] & END_OF_STREAM != 0 || page_end == bytes.len())
+}
+
+/// Reads the loop region from the `LOOPSTART` and `LOOPLENGTH` tags.
+///
+/// Without a `LOOPLENGTH`, the loop runs to the end of the file.
+fn tag_loop_region(metadata: &AudioMetadata) -> Option<LoopRegion> {
+    let start = metadata.tag("LOOPSTART")?.parse::<u64>().ok()?;
+    let length = metadata
+        .tag("LOOPLENGTH")
+        .and_then(|length| length.parse::<u64>().ok())
+        .filter(|length| *length > 0);
+
+    let end = match length {
+        // a loop past anything a file can hold is a broken tag
+        Some(length) => Some(start.checked_add(length)?),
+        None => None,
+    };
+
+    Some(LoopRegion { start, end })
+}
+
+/// Reads the normalization gain from the ReplayGain tags.
+fn tag_replay_gain(metadata: &AudioMetadata) -> Option<f32> {
+    // written like "-6.48 dB"
+    let gain = metadata.tag("REPLAYGAIN_TRACK_GAIN")?;
+    let gain = gain
+        .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+        .trim()
+        .parse::<f32>()
+        .ok()?;
+    let gain = 10f32.powf(gain / 20.);
+
+    // never clip the loudest sample
+    let peak = metadata
+        .tag("REPLAYGAIN_TRACK_PEAK")
+        .and_then(|peak| peak.parse::<f32>().ok())
+        .filter(|peak| *peak > 0.);
+
+    Some(match peak {
+        Some(peak) => gain.min(1. / peak),
+        None => gain,
+    })
+}
+
+/// Measures the normalization gain of `source`.
+fn measure_gain(source: &AudioSource) -> std::io::Result<f32> {
+    let loudness = Loudness::measure(source.clone()).map_err(invalid_data)?;
+
+    Ok(loudness.normalization_gain())
+}
+
+/// A region of audio that loops.
+///
+/// Positions are in frames at the sample rate of the file. Audio plays from
+/// the start of the file, and every time it reaches `end`, jumps back to
+/// `start` on the exact sample. This makes intro-then-loop music a single
+/// file.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash, Serialize)]
+pub struct LoopRegion {
+    /// The first frame of the loop.
+    pub start: u64,
+    /// The frame after the last frame of the loop, or `None` to loop at the
+    /// end of the file.
+    pub end: Option<u64>,
+}
+
+impl LoopRegion {
+    /// Converts the region from frames at `from` Hz to frames at `to` Hz.
+    pub fn rescale(self, from: u32, to: u32) -> LoopRegion {
+        let rescale = |frame: u64| (frame as u128 * to as u128 / from as u128) as u64;
+
+        LoopRegion {
+            start: rescale(self.start),
+            end: self.end.map(rescale),
+        }
+    }
+}
+
+/// A sound decoded ahead of time.
+///
+/// Unlike an [`AudioSource`], this plays without decoding or resampling,
+/// which makes it suited to short sounds played often, like hitsounds. The
+/// samples are stereo, and resampled to the audio device when loaded, or once
+/// the device is opened if it wasn't yet.
+///
+/// See [`AudioLoaderSettings`] for how to load one.
+#[derive(Asset, Debug, Clone, TypePath)]
+pub struct DecodedSound {
+    /// The interleaved stereo samples.
+    pub samples: Arc<[i16]>,
+    /// The sample rate of the samples.
+    pub sample_rate: u32,
+    /// The region of the sound that loops, in frames of the samples.
+    pub loop_region: Option<LoopRegion>,
+    /// The normalization gain of the sound, see [`AudioSource::gain`].
+    pub gain: f32,
+}
+
+impl DecodedSound {
+    /// Decodes an [`AudioSource`] entirely, resampling it to `sample_rate`.
+    pub fn decode(source: AudioSource, sample_rate: u32) -> std::io::Result<DecodedSound> {
+        let loop_region = source.loop_region;
+        let gain = source.gain;
+        let decoder = Decoder::new(source).map_err(invalid_data)?;
+
+        let source_rate = decoder.sample_rate();
+        let order = decoder.channel_order();
+        let converter = ChannelConverter::new(decoder, CHANNEL_COUNT as u8, order);
+        let resampler = Resampler::new(converter, sample_rate).map_err(invalid_data)?;
+
+        Ok(DecodedSound {
+            samples: read_all(resampler)?.into(),
+            sample_rate,
+            loop_region: loop_region.map(|r| r.rescale(source_rate, sample_rate)),
+            gain,
+        })
+    }
+
+    /// Resamples the sound to `sample_rate`.
+    ///
+    /// This is for sounds decoded before the audio device was opened, which
+    /// are decoded at [`DEFAULT_SAMPLE_RATE`].
+    pub fn resample(&self, sample_rate: u32) -> std::io::Result<DecodedSound> {
+        let source = DecodedSource::new(self.clone());
+        let resampler = Resampler::new(source, sample_rate).map_err(invalid_data)?;
+
+        Ok(DecodedSound {
+            samples: read_all(resampler)?.into(),
+            sample_rate,
+            loop_region: self
+                .loop_region
+                .map(|r| r.rescale(self.sample_rate, sample_rate)),
+            gain: self.gain,
+        })
+    }
+
+    /// The channel count of the sound.
+    pub fn channels(&self) -> u8 {
+        CHANNEL_COUNT as u8
+    }
+
+    /// The length of the sound.
+    pub fn duration(&self) -> Duration {
+        let frames = self.samples.len() / self.channels() as usize;
+
+        Duration::from_secs_f64(frames as f64 / self.sample_rate as f64)
+    }
+}
+
+/// The sample rate of the audio device, shared with the [`AudioLoader`] so
+/// [`DecodedSound`]s are decoded at it.
+#[derive(Clone, Debug, Default)]
+pub(super) struct OutputRate(Arc<AtomicU32>);
+
+impl OutputRate {
+    /// Returns the sample rate, or [`DEFAULT_SAMPLE_RATE`] if there is no
+    /// audio device yet.
+    pub fn get(&self) -> u32 {
+        match self.0.load(Ordering::Acquire) {
+            0 => DEFAULT_SAMPLE_RATE,
+            sample_rate => sample_rate,
+        }
+    }
+
+    pub fn set(&self, sample_rate: u32) {
+        self.0.store(sample_rate, Ordering::Release);
+    }
+}
+
+/// Samples all of `source`.
+fn read_all<S: Source>(mut source: S) -> std::io::Result<Vec<i16>>
+where
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let mut samples = Vec::new();
+    let mut buf = vec![0; DECODE_BUFFER_LEN];
+
+    loop {
+        let len = source.sample(&mut buf).map_err(invalid_data)?;
+
+        if len == 0 {
+            break;
+        }
+
+        samples.extend_from_slice(&buf[..len]);
+    }
+
+    Ok(samples)
+}
+
+fn invalid_data(err: impl std::error::Error + Send + Sync + 'static) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
+/// The container format of an [`AudioSource`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AudioFormat {
+    /// Vorbis in an Ogg container.
+    Ogg,
+    /// Uncompressed PCM in a RIFF WAVE container.
+    Wav,
+    /// Native FLAC.
+    Flac,
+}
+
+impl AudioFormat {
+    /// Detects the format of a file from its magic bytes.
+    ///
+    /// Returns `None` if the format isn't supported.
+    pub fn detect(bytes: &[u8]) -> Option<AudioFormat> {
+        match bytes {
+            [b'O', b'g', b'g', b'S', ..] => Some(AudioFormat::Ogg),
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => {
+                Some(AudioFormat::Wav)
+            }
+            [b'f', b'L', b'a', b'C', ..] => Some(AudioFormat::Flac),
+            _ => None,
+        }
+    }
 }
 
 impl AsRef<[u8]> for AudioSource {
@@ -52,3 +588,92 @@ impl AsRef<[u8]> for AudioSource {
         &self.bytes[..]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::fixtures::{impulse, peak_frame, wav};
+
+    #[test]
+    fn decoded_impulse_keeps_its_position() {
+        let source = wav(1, 44_100, &impulse(4410, 1000));
+        let sound = DecodedSound::decode(source, 48_000).unwrap();
+
+        // 1000 frames at 44.1kHz is 1088.4 frames at 48kHz
+        assert_eq!(sound.samples.len(), 4800 * CHANNEL_COUNT as usize);
+        assert!((1088..=1089).contains(&peak_frame(&sound.samples, 2)));
+    }
+
+    #[test]
+    fn resampled_impulse_keeps_its_position() {
+        let source = wav(1, 48_000, &impulse(4800, 1200));
+        let sound = DecodedSound::decode(source, 48_000).unwrap();
+        let sound = sound.resample(32_000).unwrap();
+
+        assert_eq!(sound.samples.len(), 3200 * CHANNEL_COUNT as usize);
+        assert_eq!(peak_frame(&sound.samples, 2), 800);
+    }
+
+    fn tags(tags: &[(&str, &str)]) -> AudioMetadata {
+        AudioMetadata {
+            tags: tags
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn loop_region_is_read_from_tags() {
+        let region = tag_loop_region(&tags(&[("LOOPSTART", "100"), ("LOOPLENGTH", "200")]));
+        assert_eq!(
+            region,
+            Some(LoopRegion {
+                start: 100,
+                end: Some(300),
+            })
+        );
+
+        // without a length, the loop runs to the end of the file
+        let region = tag_loop_region(&tags(&[("loopstart", "100"), ("LOOPLENGTH", "0")]));
+        assert_eq!(
+            region,
+            Some(LoopRegion {
+                start: 100,
+                end: None,
+            })
+        );
+
+        assert_eq!(tag_loop_region(&tags(&[("LOOPLENGTH", "200")])), None);
+        assert_eq!(
+            tag_loop_region(&tags(&[
+                ("LOOPSTART", "1"),
+                ("LOOPLENGTH", &u64::MAX.to_string()),
+            ])),
+            None
+        );
+    }
+
+    #[test]
+    fn rescaled_loop_region_starts_on_the_same_audio() {
+        // the loop starts on the impulse
+        let mut source = wav(1, 44_100, &impulse(8820, 4410));
+        source.loop_region = Some(LoopRegion {
+            start: 4410,
+            end: Some(8820),
+        });
+
+        let sound = DecodedSound::decode(source, 48_000).unwrap();
+        let region = sound.loop_region.unwrap();
+
+        assert_eq!(
+            region,
+            LoopRegion {
+                start: 4800,
+                end: Some(9600),
+            }
+        );
+        assert!(peak_frame(&sound.samples, 2).abs_diff(region.start as usize) <= 1);
+    }
+}
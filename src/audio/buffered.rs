@@ -0,0 +1,484 @@
+//! Decoding audio ahead of the streamer on a worker thread.
+
+use std::cmp::min;
+use std::io;
+use std::sync::{
+    atomic::{AtomicBool, AtomicI16, AtomicU64, AtomicUsize, Ordering},
+    mpsc::{self, Receiver, Sender, TryRecvError},
+    Arc, Mutex,
+};
+use std::thread::{self, Thread};
+
+use super::control::AudioControlState;
+use super::source::{BoxedError, BoxedSource, Source};
+use super::AudioControl;
+
+/// The capacity of the ring buffer of a [`Buffered`] source, in frames.
+const RING_BUFFER_FRAMES: usize = 16_384;
+
+/// How many frames the worker decodes at once.
+const DECODE_CHUNK_FRAMES: usize = 2048;
+
+/// The thread that decodes every [`Buffered`] source of a device.
+///
+/// The worker sleeps until a [`Buffered`] has room in its ring buffer, is
+/// seeked, or is dropped, and then decodes a chunk of every source that needs
+/// one in turn. It stops once this is dropped and every source it was given
+/// is gone.
+pub(super) struct DecodeWorker {
+    jobs: Sender<Job>,
+    thread: Thread,
+}
+
+impl DecodeWorker {
+    /// Starts the worker thread.
+    pub fn spawn() -> io::Result<DecodeWorker> {
+        let (jobs, rx) = mpsc::channel();
+
+        let handle = thread::Builder::new()
+            .name(String::from("audio decoder"))
+            .spawn(move || decode_worker(rx))?;
+
+        Ok(DecodeWorker {
+            jobs,
+            thread: handle.thread().clone(),
+        })
+    }
+}
+
+impl Drop for DecodeWorker {
+    fn drop(&mut self) {
+        // the worker notices the channel closing once it wakes
+        self.thread.unpark();
+    }
+}
+
+/// A [`Source`] decoded ahead of time by a [`DecodeWorker`].
+///
+/// Sampling only copies samples out of a ring buffer, so a slow decoder or
+/// resampler can't hold up the streamer. If the worker falls behind, the
+/// missing samples are played as silence and skipped once the worker catches
+/// up, which keeps the audio in time with its timestamp. Each of these is
+/// counted in [`AudioControl::underruns`].
+///
+/// Nothing is decoded before the source is handed to the worker, so a source
+/// sampled before the worker gets to it starts with an underrun instead of
+/// holding up whoever created it. Only decoding and resampling move to the
+/// worker: the playback rate, including time-stretching, and the effects
+/// still run in the streamer.
+pub(super) struct Buffered {
+    shared: Arc<Shared>,
+    worker: Thread,
+    control: Arc<AudioControlState>,
+    sample_rate: u32,
+    channels: u8,
+    /// The last seek requested.
+    seek_epoch: u64,
+    /// Samples played as silence that the worker still owes.
+    owed: usize,
+}
+
+/// The state shared between a [`Buffered`] and the worker.
+struct Shared {
+    ring: RingBuffer,
+    channels: usize,
+    seek_position: AtomicUsize,
+    seek_requested: AtomicU64,
+    seek_done: AtomicU64,
+    /// The write position of the ring buffer when the last seek was done;
+    /// everything before it is from before the seek.
+    seek_mark: AtomicUsize,
+    ended: AtomicBool,
+    closed: AtomicBool,
+    failed: AtomicBool,
+    error: Mutex<Option<BoxedError>>,
+}
+
+/// A source being decoded by the worker.
+struct Job {
+    source: BoxedSource,
+    shared: Arc<Shared>,
+    buf: Vec<i16>,
+    seek_epoch: u64,
+}
+
+impl Buffered {
+    /// Hands `source` to `worker` to be decoded.
+    pub fn new(
+        source: BoxedSource,
+        control: &AudioControl,
+        worker: &DecodeWorker,
+    ) -> io::Result<Buffered> {
+        let sample_rate = source.sample_rate();
+        let channels = source.channels();
+
+        let shared = Arc::new(Shared {
+            ring: RingBuffer::new(RING_BUFFER_FRAMES * channels as usize),
+            channels: channels as usize,
+            seek_position: AtomicUsize::new(0),
+            seek_requested: AtomicU64::new(0),
+            seek_done: AtomicU64::new(0),
+            seek_mark: AtomicUsize::new(0),
+            ended: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            failed: AtomicBool::new(false),
+            error: Mutex::new(None),
+        });
+
+        let job = Job {
+            source,
+            shared: shared.clone(),
+            buf: vec![0; DECODE_CHUNK_FRAMES * channels as usize],
+            seek_epoch: 0,
+        };
+
+        worker
+            .jobs
+            .send(job)
+            .map_err(|_| io::Error::other("audio decoder stopped"))?;
+        worker.thread.unpark();
+
+        Ok(Buffered {
+            shared,
+            worker: worker.thread.clone(),
+            control: control.inner.clone(),
+            sample_rate,
+            channels,
+            seek_epoch: 0,
+            owed: 0,
+        })
+    }
+}
+
+impl Source for Buffered {
+    type Error = BoxedError;
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> u8 {
+        self.channels
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let shared = &self.shared;
+
+        if shared.failed.load(Ordering::Acquire) {
+            let error = shared.error.lock().ok().and_then(|mut e| e.take());
+
+            return Err(error.unwrap_or_else(|| "audio decoder failed".into()));
+        }
+
+        if shared.seek_done.load(Ordering::Acquire) != self.seek_epoch {
+            // the worker hasn't seeked yet
+            buf.fill(0);
+            self.owed += buf.len();
+
+            return Ok(buf.len());
+        }
+
+        shared
+            .ring
+            .skip_to(shared.seek_mark.load(Ordering::Acquire));
+
+        // catch up on samples played as silence
+        self.owed -= shared.ring.skip(self.owed, shared.channels);
+
+        // check before reading, so nothing can be written after
+        let ended = shared.ended.load(Ordering::Acquire);
+        let read = shared.ring.pop(buf, shared.channels);
+
+        // wake the worker before the ring buffer runs low
+        if !ended && shared.ring.free() >= shared.ring.capacity() / 2 {
+            self.worker.unpark();
+        }
+
+        if read == buf.len() || ended {
+            return Ok(read);
+        }
+
+        buf[read..].fill(0);
+        self.owed += buf.len() - read;
+        self.control.underruns.fetch_add(1, Ordering::Relaxed);
+
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        self.seek_epoch += 1;
+        self.owed = 0;
+
+        self.shared.seek_position.store(position, Ordering::Release);
+        self.shared
+            .seek_requested
+            .store(self.seek_epoch, Ordering::Release);
+        self.worker.unpark();
+
+        Ok(())
+    }
+}
+
+impl Drop for Buffered {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::Release);
+        self.worker.unpark();
+    }
+}
+
+impl Shared {
+    fn fail(&self, err: BoxedError) {
+        if let Ok(mut slot) = self.error.lock() {
+            *slot = Some(err);
+        }
+
+        self.failed.store(true, Ordering::Release);
+    }
+}
+
+impl Job {
+    /// Whether the [`Buffered`] of this job is gone, or the job failed.
+    fn is_done(&self) -> bool {
+        self.shared.closed.load(Ordering::Acquire) || self.shared.failed.load(Ordering::Acquire)
+    }
+
+    /// Seeks if a seek was requested, then decodes a chunk if there is room
+    /// for it, returning `true` if there was anything to do.
+    fn service(&mut self) -> bool {
+        let shared = &*self.shared;
+        let requested = shared.seek_requested.load(Ordering::Acquire);
+
+        if requested != self.seek_epoch {
+            let position = shared.seek_position.load(Ordering::Acquire);
+
+            if let Err(err) = self.source.seek(position) {
+                shared.fail(err);
+                return false;
+            }
+
+            shared.ended.store(false, Ordering::Release);
+            shared
+                .seek_mark
+                .store(shared.ring.write_position(), Ordering::Release);
+            shared.seek_done.store(requested, Ordering::Release);
+
+            self.seek_epoch = requested;
+        }
+
+        if shared.ended.load(Ordering::Acquire) || shared.ring.free() < self.buf.len() {
+            return false;
+        }
+
+        match self.source.sample(&mut self.buf) {
+            Ok(0) => shared.ended.store(true, Ordering::Release),
+            Ok(len) => {
+                // there is room for all of it
+                shared.ring.push(&self.buf[..len], shared.channels);
+            }
+            Err(err) => {
+                shared.fail(err);
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+fn decode_worker(rx: Receiver<Job>) {
+    let mut jobs = Vec::<Job>::new();
+    let mut disconnected = false;
+
+    loop {
+        while !disconnected {
+            match rx.try_recv() {
+                Ok(job) => jobs.push(job),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => disconnected = true,
+            }
+        }
+
+        jobs.retain(|job| !job.is_done());
+
+        if disconnected && jobs.is_empty() {
+            return;
+        }
+
+        // a chunk of each source in turn, so no source starves the others
+        let mut busy = false;
+
+        for job in jobs.iter_mut() {
+            busy |= job.service();
+        }
+
+        if !busy {
+            // anything that needs the worker unparks it, and an unpark
+            // before this makes it return right away
+            thread::park();
+        }
+    }
+}
+
+/// A lock-free ring buffer of samples with a single producer and a single
+/// consumer.
+///
+/// Positions are counted in samples ever written or read, and wrap around
+/// the buffer.
+struct RingBuffer {
+    samples: Box<[AtomicI16]>,
+    write: AtomicUsize,
+    read: AtomicUsize,
+}
+
+impl RingBuffer {
+    fn new(capacity: usize) -> RingBuffer {
+        RingBuffer {
+            samples: (0..capacity).map(|_| AtomicI16::new(0)).collect(),
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+        }
+    }
+
+    /// How many samples the buffer holds.
+    fn capacity(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// The write position of the buffer.
+    fn write_position(&self) -> usize {
+        self.write.load(Ordering::Acquire)
+    }
+
+    /// How many samples can be pushed.
+    fn free(&self) -> usize {
+        let len = self
+            .write
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.read.load(Ordering::Acquire));
+
+        self.samples.len() - len
+    }
+
+    /// Pushes whole frames of `samples`, returning how many samples were
+    /// pushed.
+    ///
+    /// Only the producer may call this.
+    fn push(&self, samples: &[i16], channels: usize) -> usize {
+        let write = self.write.load(Ordering::Relaxed);
+        let read = self.read.load(Ordering::Acquire);
+
+        let free = self.samples.len() - write.wrapping_sub(read);
+        let mut len = min(free, samples.len());
+        len -= len % channels;
+
+        for (i, sample) in samples[..len].iter().enumerate() {
+            let index = write.wrapping_add(i) % self.samples.len();
+            self.samples[index].store(*sample, Ordering::Relaxed);
+        }
+
+        self.write.store(write.wrapping_add(len), Ordering::Release);
+
+        len
+    }
+
+    /// Pops whole frames into `buf`, returning how many samples were popped.
+    ///
+    /// Only the consumer may call this.
+    fn pop(&self, buf: &mut [i16], channels: usize) -> usize {
+        let read = self.read.load(Ordering::Relaxed);
+        let write = self.write.load(Ordering::Acquire);
+
+        let mut len = min(write.wrapping_sub(read), buf.len());
+        len -= len % channels;
+
+        for (i, sample) in buf[..len].iter_mut().enumerate() {
+            let index = read.wrapping_add(i) % self.samples.len();
+            *sample = self.samples[index].load(Ordering::Relaxed);
+        }
+
+        self.read.store(read.wrapping_add(len), Ordering::Release);
+
+        len
+    }
+
+    /// Discards up to `len` samples in whole frames, returning how many were
+    /// discarded.
+    ///
+    /// Only the consumer may call this.
+    fn skip(&self, len: usize, channels: usize) -> usize {
+        let read = self.read.load(Ordering::Relaxed);
+        let write = self.write.load(Ordering::Acquire);
+
+        let mut len = min(write.wrapping_sub(read), len);
+        len -= len % channels;
+
+        self.read.store(read.wrapping_add(len), Ordering::Release);
+
+        len
+    }
+
+    /// Discards everything before `position`.
+    ///
+    /// Only the consumer may call this.
+    fn skip_to(&self, position: usize) {
+        let read = self.read.load(Ordering::Relaxed);
+
+        // positions wrap, so compare by distance
+        if (position.wrapping_sub(read) as isize) > 0 {
+            self.read.store(position, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::fixtures::wav;
+    use crate::audio::source::WavDecoder;
+
+    use std::time::{Duration, Instant};
+
+    /// Waits until the worker has decoded all of `buffered`.
+    fn wait_for_worker(buffered: &Buffered) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while !buffered.shared.ended.load(Ordering::Acquire) {
+            assert!(Instant::now() < deadline, "the worker never got to it");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn one_worker_decodes_every_source() {
+        let worker = DecodeWorker::spawn().unwrap();
+        let control = AudioControl::default();
+
+        // a few chunks each, so the worker has to take turns
+        let samples = (0..3)
+            .map(|i| {
+                (0..3 * DECODE_CHUNK_FRAMES as i16)
+                    .flat_map(|f| [f, i - f])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut sources = samples
+            .iter()
+            .map(|samples| {
+                let decoder = WavDecoder::new(wav(2, 48_000, samples)).unwrap();
+                Buffered::new(decoder.boxed(), &control, &worker).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        for (source, samples) in sources.iter_mut().zip(&samples) {
+            wait_for_worker(source);
+
+            let mut buf = vec![0; samples.len() + 2];
+            let len = source.sample(&mut buf).unwrap();
+
+            assert_eq!(buf[..len], samples[..]);
+        }
+
+        assert_eq!(control.underruns(), 0);
+    }
+}
@@ -0,0 +1,1066 @@
+//! Controlling audio while it plays.
+
+use bevy::prelude::*;
+
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::analysis::AnalysisTap;
+use super::source::{
+    Biquad, BoxedError, BoxedSource, Ducking, Fade, FadeCurve, FilterKind, Reverb, Source,
+    TimeStretch, Varispeed,
+};
+use super::DEFAULT_SAMPLE_RATE;
+
+/// A component for audio source control.
+///
+/// # Note
+/// The buffer for `cpal` on most platforms (including WASM, the platform this
+/// game will ship on!) is quite large. Controlling audio may have delays of
+/// multiple frames, and the [`AudioControl::timestamp`] function may record
+/// large jumps in timestamp. [`AudioControl::position`] accounts for this,
+/// estimating what is actually coming out of the speakers.
+#[derive(Clone, Component)]
+pub struct AudioControl {
+    pub(super) sample_rate: u32,
+    pub(super) inner: Arc<AudioControlState>,
+}
+
+impl AudioControl {
+    /// Creates an `AudioControl` for audio that starts when `timeline`
+    /// reaches `at`.
+    ///
+    /// The audio is queued like any other, but stays
+    /// [`PlaybackState::Pending`] until `at`, where the streamer starts it on
+    /// the exact sample. If `timeline` is already past `at`, the audio starts
+    /// right away.
+    ///
+    /// For example, to play a sound on beat 32 of the main track:
+    /// ```ignore
+    /// let actl = AudioControl::scheduled(main_track, rhythm.beat_position(32.));
+    /// ```
+    pub fn scheduled(timeline: &AudioControl, at: Duration) -> AudioControl {
+        let schedule = Schedule {
+            timeline: timeline.inner.clone(),
+            at,
+        };
+
+        AudioControl::new(Some(schedule), None)
+    }
+
+    /// Creates an `AudioControl` for audio that crossfades from `from` over
+    /// `duration`.
+    ///
+    /// When the audio starts, it fades in while `from` fades out, both with
+    /// [`FadeCurve::EqualPower`] so the loudness stays even. `from` is
+    /// stopped once it is silent. Nothing fades until the audio actually
+    /// starts, so a slow load never leaves a gap.
+    pub fn crossfade(from: &AudioControl, duration: Duration) -> AudioControl {
+        let crossfade = Crossfade {
+            from: from.inner.clone(),
+            duration,
+        };
+        let actl = AudioControl::new(None, Some(crossfade));
+
+        actl.send_fade(Some(0.), 1., duration, FadeCurve::EqualPower, false);
+
+        actl
+    }
+
+    fn new(schedule: Option<Schedule>, crossfade: Option<Crossfade>) -> AudioControl {
+        let (commands, command_rx) = channel();
+
+        AudioControl {
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            inner: Arc::new(AudioControlState {
+                timestamp: AtomicU64::new(0),
+                underruns: AtomicU64::new(0),
+                loops: Arc::default(),
+                state: AtomicU8::new(PlaybackState::Pending as u8),
+                volume: AtomicF32::new(1.),
+                normalization: AtomicF32::new(1.),
+                pan: AtomicF32::new(0.),
+                rate: AtomicF32::new(1.),
+                rate_mode: AtomicU8::new(RateMode::default() as u8),
+                commands,
+                command_rx: Mutex::new(command_rx),
+                error: Mutex::new(None),
+                clock: Mutex::new(None),
+                pending_seek: AtomicU64::new(NO_PENDING_SEEK),
+                low_pass: AtomicF32::new(0.),
+                high_pass: AtomicF32::new(0.),
+                reverb: AtomicF32::new(0.),
+                fade: FadeRequest::default(),
+                fade_gain: AtomicF32::new(1.),
+                duck: Mutex::new(None),
+                level: AtomicF32::new(0.),
+                tap: AnalysisTap::new(),
+                queue: Mutex::new(VecDeque::new()),
+                played_sources: AtomicUsize::new(0),
+                source_start: AtomicU64::new(0),
+                schedule,
+                crossfade,
+            }),
+        }
+    }
+
+    /// Returns the audible song position as a [`Duration`].
+    ///
+    /// This is estimated from when the streamer last mixed the audio and the
+    /// output latency of the device, so unlike [`AudioControl::timestamp`] it
+    /// advances smoothly and lines up with what the player hears.
+    pub fn position(&self) -> Duration {
+        // a seek the streamer hasn't done yet is where the audio will be
+        let pending_seek = self.inner.pending_seek.load(Ordering::Acquire);
+
+        if pending_seek != NO_PENDING_SEEK {
+            return Duration::from_nanos(pending_seek);
+        }
+
+        let timestamp = self.timestamp();
+
+        let audible = match self.inner.clock.lock().ok().and_then(|clock| *clock) {
+            Some(clock) => clock.estimate(Instant::now(), self.sample_rate),
+            None => timestamp,
+        };
+
+        // what hasn't been mixed can't be heard yet
+        samples_to_duration(audible.min(timestamp), self.sample_rate)
+    }
+
+    /// Returns the timestamp of the audio in samples.
+    ///
+    /// This is how many samples have been handed to the audio device, which
+    /// is ahead of what can be heard by [`AudioControl::latency`].
+    pub fn timestamp(&self) -> u64 {
+        self.inner.timestamp.load(Ordering::Acquire)
+    }
+
+    /// Returns the sample rate of the audio device, which
+    /// [`AudioControl::timestamp`] counts samples of.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Returns the output latency of the audio device, the time between
+    /// audio being mixed and it being heard.
+    ///
+    /// This is `None` until the audio is first mixed.
+    pub fn latency(&self) -> Option<Duration> {
+        self.inner
+            .clock
+            .lock()
+            .ok()
+            .and_then(|clock| clock.map(|c| c.latency))
+    }
+
+    /// Returns how many times the audio ran out of decoded samples.
+    ///
+    /// Each of these is a short dropout in the audio. This is useful for
+    /// diagnosing crackles on slow machines.
+    pub fn underruns(&self) -> u64 {
+        self.inner.underruns.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many times the audio has looped its
+    /// [`LoopRegion`](super::LoopRegion).
+    ///
+    /// Unlike [`AudioControl::timestamp`], which keeps counting through
+    /// loops, this goes up by one every time the audio jumps back to the
+    /// start of the loop. Audio decoded ahead of time may count a loop
+    /// slightly before it is heard. With an [`AudioQueue`](super::AudioQueue),
+    /// this counts the loops of the source playing now.
+    pub fn loop_count(&self) -> u64 {
+        self.inner.loops.load(Ordering::Acquire)
+    }
+
+    /// Returns the playback state of the audio.
+    pub fn state(&self) -> PlaybackState {
+        PlaybackState::from_u8(self.inner.state.load(Ordering::Acquire))
+    }
+
+    /// Returns the error that stopped the audio, if it failed.
+    pub fn error(&self) -> Option<String> {
+        if self.state() != PlaybackState::Failed {
+            return None;
+        }
+
+        self.inner.error.lock().ok().and_then(|error| error.clone())
+    }
+
+    /// Returns the volume of the audio.
+    pub fn volume(&self) -> f32 {
+        self.inner.volume.load()
+    }
+
+    /// Sets the volume of the audio, where `1.0` is unchanged.
+    ///
+    /// Changes are smoothed over a few milliseconds so they do not click.
+    pub fn set_volume(&self, volume: f32) {
+        self.inner.volume.store(volume.max(0.));
+    }
+
+    /// Returns the stereo pan of the audio.
+    pub fn pan(&self) -> f32 {
+        self.inner.pan.load()
+    }
+
+    /// Sets the stereo pan of the audio, from `-1.0` (left) to `1.0` (right).
+    ///
+    /// Like [`AudioControl::set_volume`], changes are smoothed.
+    pub fn set_pan(&self, pan: f32) {
+        self.inner.pan.store(pan.clamp(-1., 1.));
+    }
+
+    /// Returns the playback rate of the audio.
+    pub fn rate(&self) -> f32 {
+        self.inner.rate.load()
+    }
+
+    /// Returns how the playback rate is applied.
+    pub fn rate_mode(&self) -> RateMode {
+        RateMode::from_u8(self.inner.rate_mode.load(Ordering::Acquire))
+    }
+
+    /// Sets the playback rate of the audio, where `2.0` plays twice as fast.
+    ///
+    /// The rate is clamped to [`MIN_RATE`]..=[`MAX_RATE`], and NaN or
+    /// infinite rates are ignored.
+    /// [`AudioControl::timestamp`] keeps counting in samples of the audio, so
+    /// it advances faster or slower with the rate.
+    pub fn set_rate(&self, rate: f32, mode: RateMode) {
+        if !rate.is_finite() {
+            return;
+        }
+
+        self.inner.rate.store(rate.clamp(MIN_RATE, MAX_RATE));
+        self.inner.rate_mode.store(mode as u8, Ordering::Release);
+    }
+
+    /// Returns the cutoff of the low-pass filter in Hz, if it is on.
+    pub fn low_pass(&self) -> Option<f32> {
+        Some(self.inner.low_pass.load()).filter(|cutoff| *cutoff > 0.)
+    }
+
+    /// Muffles the audio with a low-pass filter at `cutoff` Hz, or turns the
+    /// filter off with `None`.
+    pub fn set_low_pass(&self, cutoff: Option<f32>) {
+        self.inner.low_pass.store(cutoff.map_or(0., |c| c.max(1.)));
+    }
+
+    /// Returns the cutoff of the high-pass filter in Hz, if it is on.
+    pub fn high_pass(&self) -> Option<f32> {
+        Some(self.inner.high_pass.load()).filter(|cutoff| *cutoff > 0.)
+    }
+
+    /// Thins the audio with a high-pass filter at `cutoff` Hz, or turns the
+    /// filter off with `None`.
+    pub fn set_high_pass(&self, cutoff: Option<f32>) {
+        self.inner.high_pass.store(cutoff.map_or(0., |c| c.max(1.)));
+    }
+
+    /// Returns how much of the audio is reverb.
+    pub fn reverb(&self) -> f32 {
+        self.inner.reverb.load()
+    }
+
+    /// Sets how much of the audio is reverb, from `0.0` (off) to `1.0` (all
+    /// reverb).
+    pub fn set_reverb(&self, mix: f32) {
+        self.inner.reverb.store(mix.clamp(0., 1.));
+    }
+
+    /// Fades the audio from its current fade gain to `to` over `duration`.
+    ///
+    /// The fade gain is applied on top of [`AudioControl::volume`], so fades
+    /// and volume sliders don't fight. To crossfade two tracks, fade one to
+    /// `0.0` and the other to `1.0` with [`FadeCurve::EqualPower`].
+    pub fn fade(&self, to: f32, duration: Duration, curve: FadeCurve) {
+        self.send_fade(None, to, duration, curve, false);
+    }
+
+    /// Fades the audio in from silence over `duration`.
+    pub fn fade_in(&self, duration: Duration, curve: FadeCurve) {
+        self.send_fade(Some(0.), 1., duration, curve, false);
+    }
+
+    /// Fades the audio out over `duration`, then stops it.
+    ///
+    /// Another fade before the audio is silent cancels the stop.
+    pub fn fade_out(&self, duration: Duration, curve: FadeCurve) {
+        self.send_fade(None, 0., duration, curve, true);
+    }
+
+    /// Returns the fade gain of the audio, as last mixed.
+    pub fn fade_gain(&self) -> f32 {
+        self.inner.fade_gain.load()
+    }
+
+    /// Ducks the audio to `depth` while `key` is audible.
+    ///
+    /// This is sidechain ducking: for example, ducking music under voice
+    /// lines to `0.3` keeps the voice lines clear. Ducking fades in and out
+    /// smoothly.
+    pub fn duck_under(&self, key: &AudioControl, depth: f32) {
+        if let Ok(mut duck) = self.inner.duck.lock() {
+            *duck = Some(Duck {
+                key: key.inner.clone(),
+                depth: depth.clamp(0., 1.),
+            });
+        }
+    }
+
+    /// Stops ducking the audio.
+    pub fn stop_ducking(&self) {
+        if let Ok(mut duck) = self.inner.duck.lock() {
+            *duck = None;
+        }
+    }
+
+    /// Returns the peak level of the audio as last mixed, where `1.0` is full
+    /// scale.
+    pub fn level(&self) -> f32 {
+        self.inner.level.load()
+    }
+
+    /// Pauses the audio.
+    ///
+    /// While paused, the audio is silent and [`AudioControl::timestamp`] does
+    /// not advance. Audio [scheduled](AudioControl::scheduled) for later
+    /// that is paused before it starts stays paused once it starts.
+    pub fn pause(&self) {
+        self.send(AudioCommand::Pause);
+    }
+
+    /// Resumes paused audio.
+    ///
+    /// This does nothing if the audio isn't paused. Scheduled audio that
+    /// hasn't started yet goes back to waiting for its start.
+    pub fn resume(&self) {
+        self.send(AudioCommand::Resume);
+    }
+
+    /// Stops the audio for good, removing it from the mix.
+    pub fn stop(&self) {
+        self.send(AudioCommand::Stop);
+    }
+
+    /// Plays the audio from the start, resuming it if paused.
+    ///
+    /// Stopped and finished audio is already out of the mix, so this does
+    /// nothing to it; play it again with a new `AudioControl` instead.
+    pub fn restart(&self) {
+        self.seek(Duration::ZERO);
+        self.send(AudioCommand::Resume);
+    }
+
+    /// Requests the audio to seek to `position`.
+    ///
+    /// The seek is done by the streamer the next time it mixes this audio,
+    /// after which [`AudioControl::timestamp`] will count from `position`.
+    /// [`AudioControl::position`] is `position` right away.
+    pub fn seek(&self, position: Duration) {
+        self.inner
+            .pending_seek
+            .store(position.as_nanos() as u64, Ordering::Release);
+        self.send(AudioCommand::Seek(position));
+    }
+
+    /// Checks if audio queued with an [`AudioQueue`] is still waiting to
+    /// play.
+    ///
+    /// [`AudioQueue`]: super::AudioQueue
+    pub fn has_queued(&self) -> bool {
+        self.inner.queue.lock().is_ok_and(|queue| !queue.is_empty())
+    }
+
+    /// Applies the queue, rate and effects of this control to a source, as
+    /// is done to everything played on the device.
+    ///
+    /// The rate is applied in the streamer, so changes are heard immediately
+    /// and timestamps stay accurate. Effects come after the rate, so filter
+    /// cutoffs stay put when the rate changes.
+    ///
+    /// Volume, pan, fades, pauses and seeks are applied by the
+    /// [`Mixer`](super::Mixer) the source is added to with this control. To
+    /// render a voice offline, add it to a mixer and render that:
+    /// ```ignore
+    /// let mut mixer = Mixer::new(48_000, 2);
+    /// mixer.add(actl.controlled(source), actl.clone(), AudioBus::Music);
+    /// render_wav_file(&mut mixer, Some(frames), "replay.wav")?;
+    /// ```
+    pub fn controlled(&self, source: BoxedSource) -> BoxedSource {
+        let queued = Queued::new(source, self);
+
+        Box::new(EffectsControlled::new(
+            RateControlled::new(queued, self),
+            self,
+        ))
+    }
+
+    fn send(&self, command: AudioCommand) {
+        // the receiver lives as long as the state does
+        let _ = self.inner.commands.send(command);
+    }
+
+    fn send_fade(
+        &self,
+        from: Option<f32>,
+        to: f32,
+        duration: Duration,
+        curve: FadeCurve,
+        stop: bool,
+    ) {
+        self.send(AudioCommand::Fade {
+            from,
+            to,
+            duration,
+            curve,
+            stop,
+        });
+    }
+}
+
+impl Default for AudioControl {
+    /// Creates an unheaded `AudioControl`.
+    fn default() -> Self {
+        AudioControl::new(None, None)
+    }
+}
+
+/// The playback state of audio.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum PlaybackState {
+    /// The audio has not been picked up by the streamer yet.
+    #[default]
+    Pending,
+    /// The audio is playing.
+    Playing,
+    /// The audio is paused.
+    Paused,
+    /// The audio was stopped with [`AudioControl::stop`].
+    Stopped,
+    /// The audio played to the end.
+    Finished,
+    /// The audio stopped because of an error.
+    ///
+    /// See [`AudioControl::error`].
+    Failed,
+}
+
+impl PlaybackState {
+    fn from_u8(state: u8) -> PlaybackState {
+        match state {
+            0 => PlaybackState::Pending,
+            1 => PlaybackState::Playing,
+            2 => PlaybackState::Paused,
+            3 => PlaybackState::Stopped,
+            4 => PlaybackState::Finished,
+            _ => PlaybackState::Failed,
+        }
+    }
+}
+
+/// The slowest playback rate.
+pub const MIN_RATE: f32 = 0.25;
+
+/// The fastest playback rate.
+pub const MAX_RATE: f32 = 4.;
+
+/// How a playback rate is applied to audio.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum RateMode {
+    /// The audio is resampled, so pitch goes up and down with the rate.
+    ///
+    /// Affectionately called "nightcore" mode.
+    Nightcore,
+    /// The audio is time-stretched, keeping its pitch.
+    #[default]
+    TimeStretch,
+}
+
+impl RateMode {
+    fn from_u8(mode: u8) -> RateMode {
+        match mode {
+            0 => RateMode::Nightcore,
+            _ => RateMode::TimeStretch,
+        }
+    }
+}
+
+/// A command sent from an [`AudioControl`] to the streamer.
+#[derive(Clone, Copy, Debug)]
+pub(super) enum AudioCommand {
+    Pause,
+    Resume,
+    Stop,
+    Seek(Duration),
+    /// Fades from `from`, or the current gain if `None`, to `to`.
+    Fade {
+        from: Option<f32>,
+        to: f32,
+        duration: Duration,
+        curve: FadeCurve,
+        stop: bool,
+    },
+}
+
+pub(super) struct AudioControlState {
+    pub timestamp: AtomicU64,
+    pub underruns: AtomicU64,
+    /// How many times the audio has looped.
+    pub loops: Arc<AtomicU64>,
+    state: AtomicU8,
+    pub volume: AtomicF32,
+    /// The normalization gain of the audio, see [`AudioSource::gain`].
+    ///
+    /// [`AudioSource::gain`]: super::AudioSource::gain
+    pub normalization: AtomicF32,
+    pub pan: AtomicF32,
+    pub rate: AtomicF32,
+    rate_mode: AtomicU8,
+    commands: Sender<AudioCommand>,
+    command_rx: Mutex<Receiver<AudioCommand>>,
+    error: Mutex<Option<String>>,
+    clock: Mutex<Option<AudibleClock>>,
+    /// The position of the last seek the streamer hasn't done yet, in
+    /// nanoseconds, or [`NO_PENDING_SEEK`].
+    pending_seek: AtomicU64,
+    low_pass: AtomicF32,
+    high_pass: AtomicF32,
+    reverb: AtomicF32,
+    fade: FadeRequest,
+    fade_gain: AtomicF32,
+    duck: Mutex<Option<Duck>>,
+    /// The peak level of the audio, as last mixed.
+    pub level: AtomicF32,
+    pub tap: AnalysisTap,
+    /// Sources to play after the current one, without a gap, with their
+    /// normalization gains.
+    pub queue: Mutex<VecDeque<(BoxedSource, f32)>>,
+    /// How many sources have finished playing, which is the index of the
+    /// source playing now.
+    pub played_sources: AtomicUsize,
+    /// When the source playing now started, in nanoseconds.
+    ///
+    /// A new stream picks this up, so the timestamp carries on across the
+    /// sources that were played before it.
+    pub source_start: AtomicU64,
+    pub schedule: Option<Schedule>,
+    crossfade: Option<Crossfade>,
+}
+
+impl AudioControlState {
+    /// Returns the playback state.
+    pub fn state(&self) -> PlaybackState {
+        PlaybackState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    /// Sets the playback state.
+    pub fn set_state(&self, state: PlaybackState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Records when the audio was last mixed.
+    ///
+    /// This never blocks; if the clock is being read, the update is skipped.
+    pub fn record_clock(&self, clock: AudibleClock) {
+        if let Ok(mut slot) = self.clock.try_lock() {
+            *slot = Some(clock);
+        }
+    }
+
+    /// Stops the clock where it is heard at `now`, so the position holds
+    /// still while paused.
+    ///
+    /// This never blocks, like [`AudioControlState::record_clock`].
+    pub fn freeze_clock(&self, now: Instant, sample_rate: u32) {
+        let timestamp = self.timestamp.load(Ordering::Acquire);
+
+        if let Ok(mut slot) = self.clock.try_lock() {
+            if let Some(clock) = slot.as_mut() {
+                *clock = AudibleClock {
+                    timestamp: clock.estimate(now, sample_rate).min(timestamp),
+                    time: now,
+                    rate: 0.,
+                    ..*clock
+                };
+            }
+        }
+    }
+
+    /// Marks the seek to `position` as done by the streamer.
+    ///
+    /// A later seek that is still pending is kept.
+    pub fn finish_seek(&self, position: Duration) {
+        let _ = self.pending_seek.compare_exchange(
+            position.as_nanos() as u64,
+            NO_PENDING_SEEK,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+    }
+
+    /// Fails the audio with an error.
+    pub fn fail(&self, error: impl Display) {
+        if let Ok(mut slot) = self.error.lock() {
+            *slot = Some(error.to_string());
+        }
+
+        self.set_state(PlaybackState::Failed);
+    }
+
+    /// Checks if the audio is done playing for good.
+    pub fn is_done(&self) -> bool {
+        matches!(
+            PlaybackState::from_u8(self.state.load(Ordering::Acquire)),
+            PlaybackState::Stopped | PlaybackState::Finished | PlaybackState::Failed
+        )
+    }
+
+    /// Requests a fade for the effects of the audio to pick up.
+    ///
+    /// This must only be called from the streamer, so a request is never
+    /// read while half-written.
+    pub fn request_fade(
+        &self,
+        from: Option<f32>,
+        to: f32,
+        duration: Duration,
+        curve: FadeCurve,
+        stop: bool,
+    ) {
+        self.fade.request(from, to, duration, curve, stop);
+    }
+
+    /// Starts fading out the audio this audio crossfades from, if any.
+    ///
+    /// The mixer calls this when the audio starts playing.
+    pub fn start_crossfade(&self) {
+        if let Some(crossfade) = &self.crossfade {
+            crossfade
+                .from
+                .fade
+                .request(None, 0., crossfade.duration, FadeCurve::EqualPower, true);
+        }
+    }
+
+    /// Checks if the audio was faded out with [`AudioControl::fade_out`] and
+    /// is now silent.
+    pub fn is_faded_out(&self) -> bool {
+        self.fade.stop.load(Ordering::Acquire) && self.fade_gain.load() == 0.
+    }
+
+    /// Takes all pending commands.
+    ///
+    /// This never blocks; if the commands are being read somewhere else, this
+    /// returns nothing.
+    pub fn drain_commands(&self, mut f: impl FnMut(AudioCommand)) {
+        if let Ok(command_rx) = self.command_rx.try_lock() {
+            while let Ok(command) = command_rx.try_recv() {
+                f(command);
+            }
+        }
+    }
+}
+
+/// When scheduled audio starts.
+pub(super) struct Schedule {
+    /// The audio whose timeline the audio is scheduled on.
+    pub timeline: Arc<AudioControlState>,
+    /// The position on the timeline.
+    pub at: Duration,
+}
+
+/// A crossfade from other audio.
+struct Crossfade {
+    /// The audio faded out.
+    from: Arc<AudioControlState>,
+    duration: Duration,
+}
+
+/// The last fade requested for audio.
+///
+/// Only the streamer writes this, either for an [`AudioCommand::Fade`] or to
+/// start a crossfade, and only the streamer reads it. It is atomics so the
+/// shared state stays `Sync`.
+struct FadeRequest {
+    /// Bumped on every request, so the streamer can tell a new one apart.
+    serial: AtomicU64,
+    /// The gain to start from, or NaN to start from the current gain.
+    from: AtomicF32,
+    to: AtomicF32,
+    /// The length of the fade, in seconds.
+    duration: AtomicF32,
+    curve: AtomicU8,
+    /// Whether the audio is stopped once it fades to silence.
+    stop: AtomicBool,
+}
+
+impl FadeRequest {
+    fn request(
+        &self,
+        from: Option<f32>,
+        to: f32,
+        duration: Duration,
+        curve: FadeCurve,
+        stop: bool,
+    ) {
+        self.from.store(from.unwrap_or(f32::NAN));
+        self.to.store(to.max(0.));
+        self.duration.store(duration.as_secs_f32());
+        self.curve.store(curve as u8, Ordering::Release);
+        self.stop.store(stop, Ordering::Release);
+        self.serial.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn curve(&self) -> FadeCurve {
+        match self.curve.load(Ordering::Acquire) {
+            0 => FadeCurve::Linear,
+            _ => FadeCurve::EqualPower,
+        }
+    }
+}
+
+impl Default for FadeRequest {
+    fn default() -> Self {
+        FadeRequest {
+            serial: AtomicU64::new(0),
+            from: AtomicF32::new(f32::NAN),
+            to: AtomicF32::new(1.),
+            duration: AtomicF32::new(0.),
+            curve: AtomicU8::new(FadeCurve::default() as u8),
+            stop: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Ducking of audio under another.
+struct Duck {
+    /// The audio that ducks this audio while audible.
+    key: Arc<AudioControlState>,
+    /// The gain of this audio while ducked.
+    depth: f32,
+}
+
+/// The value of [`AudioControlState::pending_seek`] without a pending seek.
+const NO_PENDING_SEEK: u64 = u64::MAX;
+
+/// A point in time where the streamer mixed some audio.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct AudibleClock {
+    /// The timestamp of the first sample mixed.
+    pub timestamp: u64,
+    /// When the sample was mixed.
+    pub time: Instant,
+    /// How long after being mixed the sample is heard.
+    pub latency: Duration,
+    /// The playback rate it was mixed at.
+    pub rate: f32,
+    /// The timestamp the audio was last seeked to.
+    ///
+    /// Audio from before the seek may still be heard for the latency, but
+    /// the position never goes back past where the game asked for.
+    pub floor: u64,
+}
+
+impl AudibleClock {
+    /// Estimates the timestamp being heard at `now`.
+    fn estimate(&self, now: Instant, sample_rate: u32) -> u64 {
+        // negative while the samples are still in the device's buffer
+        let heard_for =
+            now.saturating_duration_since(self.time).as_secs_f64() - self.latency.as_secs_f64();
+        let samples = heard_for * sample_rate as f64 * self.rate as f64;
+
+        ((self.timestamp as f64 + samples).max(0.) as u64).max(self.floor)
+    }
+}
+
+/// Converts a count of samples at `sample_rate` to a [`Duration`].
+fn samples_to_duration(samples: u64, sample_rate: u32) -> Duration {
+    let nanos = samples as u128 * 1_000_000_000 / sample_rate as u128;
+
+    Duration::from_nanos(nanos as u64)
+}
+
+/// A source whose playback rate follows an [`AudioControl`].
+pub(super) struct RateControlled<T> {
+    inner: TimeStretch<Varispeed<T>>,
+    control: Arc<AudioControlState>,
+}
+
+impl<T> RateControlled<T>
+where
+    T: Source,
+{
+    /// Creates a new `RateControlled`.
+    pub fn new(inner: T, control: &AudioControl) -> RateControlled<T> {
+        RateControlled {
+            inner: TimeStretch::new(Varispeed::new(inner)),
+            control: control.inner.clone(),
+        }
+    }
+}
+
+impl<T> Source for RateControlled<T>
+where
+    T: Source,
+{
+    type Error = T::Error;
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        self.inner.channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let rate = self.control.rate.load() as f64;
+        let mode = RateMode::from_u8(self.control.rate_mode.load(Ordering::Acquire));
+
+        let (varispeed, stretch) = match mode {
+            RateMode::Nightcore => (rate, 1.),
+            RateMode::TimeStretch => (1., rate),
+        };
+
+        self.inner.set_rate(stretch);
+        self.inner.inner_mut().set_rate(varispeed);
+
+        self.inner.sample(buf)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        self.inner.seek(position)
+    }
+}
+
+/// A source whose effects follow an [`AudioControl`].
+pub(super) struct EffectsControlled<T> {
+    inner: Ducking<Fade<Reverb<Biquad<Biquad<T>>>>>,
+    control: Arc<AudioControlState>,
+    /// The serial of the last fade applied.
+    fade_serial: u64,
+}
+
+impl<T> EffectsControlled<T>
+where
+    T: Source,
+{
+    /// Creates a new `EffectsControlled`.
+    pub fn new(inner: T, control: &AudioControl) -> EffectsControlled<T> {
+        let filtered = Biquad::new(
+            Biquad::new(inner, FilterKind::HighPass),
+            FilterKind::LowPass,
+        );
+
+        EffectsControlled {
+            inner: Ducking::new(Fade::new(Reverb::new(filtered))),
+            control: control.inner.clone(),
+            fade_serial: 0,
+        }
+    }
+}
+
+impl<T> Source for EffectsControlled<T>
+where
+    T: Source,
+{
+    type Error = T::Error;
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        self.inner.channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let control = &self.control;
+
+        // the streamer can't wait on the game thread
+        if let Ok(duck) = control.duck.try_lock() {
+            let (key_level, depth) = match &*duck {
+                Some(duck) if duck.key.state() == PlaybackState::Playing => {
+                    (duck.key.level.load(), duck.depth)
+                }
+                Some(duck) => (0., duck.depth),
+                None => (0., 1.),
+            };
+
+            self.inner.set_key_level(key_level);
+            self.inner.set_depth(depth);
+        }
+
+        let fade = self.inner.inner_mut();
+        let serial = control.fade.serial.load(Ordering::Acquire);
+
+        if serial != self.fade_serial {
+            let request = &control.fade;
+            let from = request.from.load();
+            let duration = Duration::from_secs_f32(request.duration.load());
+
+            if !from.is_nan() {
+                fade.set_gain(from);
+            }
+
+            fade.fade_to(request.to.load(), duration, request.curve());
+            self.fade_serial = serial;
+        }
+
+        let reverb = fade.inner_mut();
+        reverb.set_mix(control.reverb.load());
+
+        let low_pass = reverb.inner_mut();
+        low_pass.set_cutoff(Some(control.low_pass.load()).filter(|c| *c > 0.));
+
+        let high_pass = low_pass.inner_mut();
+        high_pass.set_cutoff(Some(control.high_pass.load()).filter(|c| *c > 0.));
+
+        let len = self.inner.sample(buf)?;
+
+        control.fade_gain.store(self.inner.inner_mut().gain());
+
+        Ok(len)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        self.inner.seek(position)
+    }
+}
+
+/// A source that plays the sources queued on an [`AudioControl`] after it,
+/// without a gap.
+///
+/// Queued sources must have the same sample rate and channel count as the
+/// first. Seeking only moves within the source currently playing.
+pub(super) struct Queued {
+    current: BoxedSource,
+    control: Arc<AudioControlState>,
+    /// The frames played before the current source.
+    start: usize,
+    /// The frames played of the current source.
+    played: usize,
+}
+
+impl Queued {
+    /// Creates a new `Queued`.
+    ///
+    /// `first` starts at [`AudioControlState::source_start`], which is zero
+    /// unless the audio is being resumed on a new stream.
+    pub fn new(first: BoxedSource, control: &AudioControl) -> Queued {
+        let start = control.inner.source_start.load(Ordering::Acquire) as u128
+            * first.sample_rate() as u128
+            / 1_000_000_000;
+
+        Queued {
+            current: first,
+            control: control.inner.clone(),
+            start: start as usize,
+            played: 0,
+        }
+    }
+
+    /// Moves on to the next queued source, returning `false` if there is
+    /// none.
+    fn advance(&mut self) -> bool {
+        // the game thread only holds this to push a source
+        let Some((next, gain)) = self
+            .control
+            .queue
+            .try_lock()
+            .ok()
+            .and_then(|mut q| q.pop_front())
+        else {
+            return false;
+        };
+
+        self.current = next;
+        self.start += self.played;
+        self.played = 0;
+
+        let start = samples_to_duration(self.start as u64, self.current.sample_rate());
+
+        self.control.normalization.store(gain);
+        self.control.played_sources.fetch_add(1, Ordering::AcqRel);
+        self.control
+            .source_start
+            .store(start.as_nanos() as u64, Ordering::Release);
+
+        // every source counts its own loops
+        self.control.loops.store(0, Ordering::Release);
+
+        true
+    }
+}
+
+impl Source for Queued {
+    type Error = BoxedError;
+
+    fn sample_rate(&self) -> u32 {
+        self.current.sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        self.current.channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let channels = self.current.channels() as usize;
+        let mut filled = 0;
+
+        // fill the whole buffer, so the next source starts on the very next
+        // sample
+        while filled < buf.len() {
+            let len = self.current.sample(&mut buf[filled..])?;
+
+            if len == 0 && !self.advance() {
+                break;
+            }
+
+            filled += len;
+            self.played += len / channels;
+        }
+
+        Ok(filled)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        // finished sources are gone, so this can't seek back into them
+        let position = position.saturating_sub(self.start);
+
+        self.current.seek(position)?;
+        self.played = position;
+
+        Ok(())
+    }
+}
+
+/// An `f32` that can be shared with the streamer.
+pub(super) struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    pub fn new(value: f32) -> AtomicF32 {
+        AtomicF32(AtomicU32::new(value.to_bits()))
+    }
+
+    pub fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Acquire))
+    }
+
+    pub fn store(&self, value: f32) {
+        self.0.store(value.to_bits(), Ordering::Release);
+    }
+}
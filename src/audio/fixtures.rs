@@ -0,0 +1,197 @@
+//! Audio files built by hand, for tests.
+
+use std::sync::Arc;
+
+use super::{AudioFormat, AudioMetadata, AudioSource};
+
+/// `WAVE_FORMAT_PCM`
+pub const WAV_PCM: u16 = 0x0001;
+/// `WAVE_FORMAT_IEEE_FLOAT`
+pub const WAV_FLOAT: u16 = 0x0003;
+
+/// Builds a 16-bit `.wav` of interleaved `samples`.
+pub fn wav(channels: u16, sample_rate: u32, samples: &[i16]) -> AudioSource {
+    let data = samples
+        .iter()
+        .flat_map(|s| s.to_le_bytes())
+        .collect::<Vec<_>>();
+
+    wav_raw(WAV_PCM, channels, sample_rate, 16, &data)
+}
+
+/// Builds a `.wav` of raw sample `data`, in the format `format_tag` with
+/// `bits` bits per sample.
+pub fn wav_raw(
+    format_tag: u16,
+    channels: u16,
+    sample_rate: u32,
+    bits: u16,
+    data: &[u8],
+) -> AudioSource {
+    let block_align = channels * bits / 8;
+
+    let mut bytes = Vec::new();
+    bytes.extend(b"RIFF");
+    bytes.extend((36 + data.len() as u32).to_le_bytes());
+    bytes.extend(b"WAVEfmt ");
+    bytes.extend(16u32.to_le_bytes());
+    bytes.extend(format_tag.to_le_bytes());
+    bytes.extend(channels.to_le_bytes());
+    bytes.extend(sample_rate.to_le_bytes());
+    bytes.extend((sample_rate * block_align as u32).to_le_bytes());
+    bytes.extend(block_align.to_le_bytes());
+    bytes.extend(bits.to_le_bytes());
+    bytes.extend(b"data");
+    bytes.extend((data.len() as u32).to_le_bytes());
+    bytes.extend(data);
+
+    source(bytes, AudioFormat::Wav)
+}
+
+/// Builds a 16-bit `.flac` of interleaved `samples`, in blocks of
+/// `block_size` frames.
+///
+/// Every frame is stored verbatim. With `seek_every` blocks, a SEEKTABLE
+/// points at every `seek_every`th block.
+pub fn flac(
+    channels: u8,
+    sample_rate: u32,
+    block_size: u16,
+    samples: &[i16],
+    seek_every: Option<usize>,
+) -> AudioSource {
+    let frames = samples.len() as u64 / channels as u64;
+
+    let mut blocks = Vec::new();
+    let mut offsets = Vec::new();
+
+    for (number, block) in samples
+        .chunks(block_size as usize * channels as usize)
+        .enumerate()
+    {
+        offsets.push(blocks.len() as u64);
+
+        // fixed block size, size and bits per sample in the header
+        let start = blocks.len();
+        let len = block.len() / channels as usize;
+        assert!(number < 128, "frame numbers are written in one byte");
+
+        blocks.extend([0xff, 0xf8, 0x70, (channels - 1) << 4 | 0x08, number as u8]);
+        blocks.extend((len as u16 - 1).to_be_bytes());
+        blocks.push(crc8(&blocks[start..]));
+
+        // verbatim subframes
+        for channel in 0..channels as usize {
+            blocks.push(0x02);
+            blocks.extend(
+                block
+                    .iter()
+                    .skip(channel)
+                    .step_by(channels as usize)
+                    .flat_map(|s| s.to_be_bytes()),
+            );
+        }
+
+        let crc = crc16(&blocks[start..]);
+        blocks.extend(crc.to_be_bytes());
+    }
+
+    let mut streaminfo = Vec::new();
+    streaminfo.extend(block_size.to_be_bytes());
+    streaminfo.extend(block_size.to_be_bytes());
+    streaminfo.extend([0; 6]);
+    // 20 bits of rate, 3 of channels, 5 of bits per sample, 36 of frames
+    let packed = (sample_rate as u64) << 44 | ((channels as u64 - 1) << 41) | (15 << 36) | frames;
+    streaminfo.extend(packed.to_be_bytes());
+    streaminfo.extend([0; 16]);
+
+    let seek_table = seek_every.map(|every| {
+        offsets
+            .iter()
+            .enumerate()
+            .step_by(every)
+            .flat_map(|(i, offset)| {
+                let frame = i as u64 * block_size as u64;
+
+                frame
+                    .to_be_bytes()
+                    .into_iter()
+                    .chain(offset.to_be_bytes())
+                    .chain(block_size.to_be_bytes())
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut bytes = b"fLaC".to_vec();
+    let mut metadata_block = |kind: u8, data: &[u8], last: bool| {
+        let len = (data.len() as u32).to_be_bytes();
+
+        bytes.extend([kind | if last { 0x80 } else { 0 }, len[1], len[2], len[3]]);
+        bytes.extend(data);
+    };
+
+    metadata_block(0, &streaminfo, seek_table.is_none());
+
+    if let Some(seek_table) = seek_table {
+        metadata_block(3, &seek_table, true);
+    }
+
+    bytes.extend(blocks);
+
+    source(bytes, AudioFormat::Flac)
+}
+
+/// The CRC-8 of a FLAC frame header.
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// The CRC-16 of a FLAC frame.
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ (*byte as u16) << 8, |crc, _| {
+            if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x8005
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Wraps `bytes` in an [`AudioSource`].
+pub fn source(bytes: impl Into<Arc<[u8]>>, format: AudioFormat) -> AudioSource {
+    AudioSource {
+        bytes: bytes.into(),
+        format,
+        metadata: AudioMetadata::default(),
+        loop_region: None,
+        gain: 1.,
+    }
+}
+
+/// A mono impulse of `frames` frames, with one full sample at `at`.
+pub fn impulse(frames: usize, at: usize) -> Vec<i16> {
+    let mut samples = vec![0; frames];
+    samples[at] = i16::MAX / 2;
+    samples
+}
+
+/// Returns the frame of the loudest sample of the first channel.
+pub fn peak_frame(samples: &[i16], channels: usize) -> usize {
+    samples
+        .iter()
+        .step_by(channels)
+        .enumerate()
+        .max_by_key(|(_, s)| s.unsigned_abs())
+        .map(|(i, _)| i)
+        .unwrap()
+}
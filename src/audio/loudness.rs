@@ -0,0 +1,206 @@
+//! Loudness measurement, for normalizing songs to the same volume.
+
+use super::asset::AudioSource;
+use super::source::{ChannelConverter, DecodeError, Decoder, Source};
+
+/// The loudness songs are normalized to, in LUFS.
+///
+/// This is the ReplayGain 2.0 reference level, so measured loudness and
+/// ReplayGain tags agree.
+pub const NORMALIZATION_TARGET: f32 = -18.;
+
+/// The length of a gating block, in seconds.
+const BLOCK_TIME: f64 = 0.4;
+
+/// How many steps a gating block is measured in; blocks overlap by 75%.
+const BLOCK_STEPS: usize = 4;
+
+/// Blocks quieter than this are silence, in LUFS.
+const ABSOLUTE_GATE: f64 = -70.;
+
+/// Blocks this much quieter than the ungated loudness are ignored, in LU.
+const RELATIVE_GATE: f64 = -10.;
+
+/// How many samples are decoded at once when measuring.
+const DECODE_BUFFER_LEN: usize = 8192;
+
+/// The measured loudness of some audio.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loudness {
+    /// The integrated loudness in LUFS, as in EBU R128.
+    ///
+    /// This is `-inf` for silence.
+    pub integrated: f32,
+    /// The highest sample, where `1.0` is full scale.
+    pub peak: f32,
+}
+
+impl Loudness {
+    /// Measures the loudness of `source`, decoding all of it.
+    ///
+    /// This follows ITU-R BS.1770: the audio is K-weighted, and the mean
+    /// square of gated 400ms blocks is averaged.
+    pub fn measure(source: AudioSource) -> Result<Loudness, DecodeError> {
+        let decoder = Decoder::new(source)?;
+        let order = decoder.channel_order();
+        // front left and right are weighted the same, so stereo is enough;
+        // mono is measured as one channel, not counted twice
+        let channels = decoder.channels().min(2);
+        let mut source = ChannelConverter::new(decoder, channels, order);
+
+        let sample_rate = source.sample_rate() as f64;
+        let step_frames = ((BLOCK_TIME / BLOCK_STEPS as f64) * sample_rate).max(1.) as usize;
+
+        let mut filters = (0..channels)
+            .map(|_| KWeighting::new(sample_rate))
+            .collect::<Vec<_>>();
+        let mut steps = Vec::new();
+        let mut step_sum = 0.;
+        let mut step_len = 0;
+        let mut peak = 0f32;
+
+        let mut buf = vec![0i16; DECODE_BUFFER_LEN];
+
+        loop {
+            let len = source.sample(&mut buf)?;
+
+            if len == 0 {
+                break;
+            }
+
+            for frame in buf[..len].chunks_exact(channels as usize) {
+                for (sample, filter) in frame.iter().zip(filters.iter_mut()) {
+                    let sample = *sample as f64 / -(i16::MIN as f64);
+                    let weighted = filter.process(sample);
+
+                    step_sum += weighted * weighted;
+                    peak = peak.max(sample.abs() as f32);
+                }
+
+                step_len += 1;
+
+                if step_len == step_frames {
+                    steps.push(step_sum / step_frames as f64);
+                    step_sum = 0.;
+                    step_len = 0;
+                }
+            }
+        }
+
+        // the sum of the mean squares of every channel, per block
+        let blocks = steps
+            .windows(BLOCK_STEPS)
+            .map(|block| block.iter().sum::<f64>() / BLOCK_STEPS as f64)
+            .filter(|power| block_loudness(*power) > ABSOLUTE_GATE)
+            .collect::<Vec<_>>();
+
+        let gate = block_loudness(mean(blocks.iter().copied())) + RELATIVE_GATE;
+        let power = mean(blocks.iter().copied().filter(|p| block_loudness(*p) > gate));
+
+        Ok(Loudness {
+            integrated: block_loudness(power) as f32,
+            peak,
+        })
+    }
+
+    /// Returns the linear gain that brings this loudness to
+    /// [`NORMALIZATION_TARGET`], without clipping the peak.
+    pub fn normalization_gain(&self) -> f32 {
+        if !self.integrated.is_finite() {
+            return 1.;
+        }
+
+        let gain = 10f32.powf((NORMALIZATION_TARGET - self.integrated) / 20.);
+
+        if self.peak > 0. {
+            gain.min(1. / self.peak)
+        } else {
+            gain
+        }
+    }
+}
+
+/// The mean of `powers`, or `0` if there are none.
+fn mean(powers: impl Iterator<Item = f64>) -> f64 {
+    let (sum, len) = powers.fold((0., 0), |(sum, len), power| (sum + power, len + 1));
+
+    if len == 0 {
+        0.
+    } else {
+        sum / len as f64
+    }
+}
+
+/// The loudness of a block with a total mean square of `power`, in LUFS.
+fn block_loudness(power: f64) -> f64 {
+    -0.691 + 10. * power.log10()
+}
+
+/// The K-weighting filter of ITU-R BS.1770, for one channel.
+///
+/// This is a high shelf that models the head, followed by a high pass.
+struct KWeighting {
+    shelf: [f64; 5],
+    high_pass: [f64; 5],
+    /// The last two inputs and outputs of each stage.
+    history: [[f64; 4]; 2],
+}
+
+impl KWeighting {
+    fn new(sample_rate: f64) -> KWeighting {
+        // coefficients from the spec, derived for any sample rate
+        let shelf = {
+            let k = (std::f64::consts::PI * 1681.974450955533 / sample_rate).tan();
+            let q = 0.7071752369554196;
+            let vh = 10f64.powf(3.999843853973347 / 20.);
+            let vb = vh.powf(0.4996667741545416);
+            let a0 = 1. + k / q + k * k;
+
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2. * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+                2. * (k * k - 1.) / a0,
+                (1. - k / q + k * k) / a0,
+            ]
+        };
+
+        let high_pass = {
+            let k = (std::f64::consts::PI * 38.13547087602444 / sample_rate).tan();
+            let q = 0.5003270373238773;
+            let a0 = 1. + k / q + k * k;
+
+            [
+                1.,
+                -2.,
+                1.,
+                2. * (k * k - 1.) / a0,
+                (1. - k / q + k * k) / a0,
+            ]
+        };
+
+        KWeighting {
+            shelf,
+            high_pass,
+            history: [[0.; 4]; 2],
+        }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        let mut x = sample;
+
+        for (coefficients, history) in [self.shelf, self.high_pass]
+            .iter()
+            .zip(self.history.iter_mut())
+        {
+            let [b0, b1, b2, a1, a2] = *coefficients;
+            let [x1, x2, y1, y2] = *history;
+            let y = b0 * x + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+
+            *history = [x, x1, y, y1];
+            x = y;
+        }
+
+        x
+    }
+}
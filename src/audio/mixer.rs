@@ -0,0 +1,608 @@
+//! Mixing of concurrently playing sources.
+
+use std::convert::Infallible;
+use std::sync::{atomic::Ordering, Arc};
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+
+use dasp_sample::Sample;
+
+use super::analysis::AnalysisTap;
+use super::control::{AtomicF32, AudibleClock, AudioCommand};
+use super::source::{BoxedError, BoxedSource, Source};
+use super::{AudioControl, PlaybackState};
+
+/// The level at which the mixer starts to softly limit the mix.
+const SOFT_CLIP_THRESHOLD: f32 = 0.8;
+
+/// The time constant of gain smoothing, in seconds.
+const GAIN_SMOOTHING_TIME: f32 = 0.005;
+
+/// The bus a voice is mixed on.
+///
+/// Every bus has its own gain in [`AudioBuses`]. Audio without this component
+/// plays on [`AudioBus::Music`].
+#[derive(Clone, Copy, Component, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AudioBus {
+    /// Songs and background music.
+    #[default]
+    Music,
+    /// Sound effects, like hitsounds and UI sounds.
+    Sfx,
+}
+
+/// Gains of the mixer buses.
+///
+/// These are multiplied with the volume of each [`AudioControl`]. A settings
+/// screen can freely change this resource, and changes are applied to the
+/// audio device automatically.
+#[derive(Clone, Debug, Resource)]
+pub struct AudioBuses {
+    /// The gain of the entire mix.
+    pub master: f32,
+    /// The gain of [`AudioBus::Music`].
+    pub music: f32,
+    /// The gain of [`AudioBus::Sfx`].
+    pub sfx: f32,
+}
+
+impl Default for AudioBuses {
+    fn default() -> Self {
+        AudioBuses {
+            master: 1.,
+            music: 1.,
+            sfx: 1.,
+        }
+    }
+}
+
+/// [`AudioBuses`] shared with a [`Mixer`].
+pub(super) struct BusGains {
+    master: AtomicF32,
+    music: AtomicF32,
+    sfx: AtomicF32,
+}
+
+impl BusGains {
+    /// Sets all gains from an [`AudioBuses`].
+    pub fn set(&self, buses: &AudioBuses) {
+        self.master.store(buses.master.max(0.));
+        self.music.store(buses.music.max(0.));
+        self.sfx.store(buses.sfx.max(0.));
+    }
+
+    /// Returns the total gain of `bus`, including the master gain.
+    pub fn gain(&self, bus: AudioBus) -> f32 {
+        let bus_gain = match bus {
+            AudioBus::Music => self.music.load(),
+            AudioBus::Sfx => self.sfx.load(),
+        };
+
+        bus_gain * self.master.load()
+    }
+}
+
+impl Default for BusGains {
+    fn default() -> Self {
+        BusGains {
+            master: AtomicF32::new(1.),
+            music: AtomicF32::new(1.),
+            sfx: AtomicF32::new(1.),
+        }
+    }
+}
+
+/// A [`Source`] that sums any number of voices together.
+///
+/// Every voice is paired with the [`AudioControl`] it was started with, and
+/// the timestamp of that control advances with the samples its own voice
+/// produces. A voice is removed from the mix once its source returns no
+/// samples, or fails to sample.
+///
+/// Each voice is scaled by the volume and pan of its control and by the gain
+/// of its [`AudioBus`].
+///
+/// The mixer itself never ends; when there are no voices, it produces
+/// silence.
+pub struct Mixer {
+    sample_rate: u32,
+    channels: u8,
+
+    voices: Vec<Voice>,
+    bus_gains: Arc<BusGains>,
+    master_tap: Arc<AnalysisTap>,
+    smoothing: f32,
+    latency: Option<Duration>,
+
+    mix_buffer: Vec<f32>,
+    voice_buffer: Vec<i16>,
+}
+
+struct Voice {
+    source: BoxedSource,
+    control: AudioControl,
+    bus: AudioBus,
+    /// The timestamp of a timeline the voice waits for before starting.
+    start: Option<u64>,
+    /// Where the voice starts in the current mix, if it is waiting.
+    start_offset: Option<StartOffset>,
+    /// The current gain of the left and right channels.
+    gains: [f32; 2],
+    /// The position of the voice in samples of its source, which drifts from
+    /// the samples mixed when the playback rate is changed.
+    position: f64,
+    /// The timestamp the voice was last seeked to.
+    seeked_to: u64,
+}
+
+impl Mixer {
+    /// Creates a new, empty `Mixer`.
+    pub fn new(sample_rate: u32, channels: u8) -> Mixer {
+        Mixer {
+            sample_rate,
+            channels,
+            voices: Vec::new(),
+            bus_gains: Arc::default(),
+            master_tap: Arc::new(AnalysisTap::new()),
+            smoothing: 1. - (-1. / (GAIN_SMOOTHING_TIME * sample_rate as f32)).exp(),
+            latency: None,
+            mix_buffer: Vec::new(),
+            voice_buffer: Vec::new(),
+        }
+    }
+
+    /// Adds a voice to the mix.
+    ///
+    /// The source should already be at the sample rate and channel count of
+    /// the mixer. If the control was made with [`AudioControl::scheduled`],
+    /// the voice waits for its timeline before starting.
+    pub fn add(&mut self, source: BoxedSource, control: AudioControl, bus: AudioBus) {
+        control.inner.timestamp.store(0, Ordering::Release);
+
+        let start = control
+            .inner
+            .schedule
+            .as_ref()
+            .map(|s| duration_to_samples(s.at, self.sample_rate));
+
+        if start.is_none() {
+            control.inner.set_state(PlaybackState::Playing);
+            control.inner.start_crossfade();
+        }
+
+        // start at the desired gain instead of fading in
+        let gains = target_gains(&control, self.bus_gains.gain(bus));
+
+        self.voices.push(Voice {
+            source,
+            control,
+            bus,
+            start,
+            start_offset: None,
+            gains,
+            position: 0.,
+            seeked_to: 0,
+        });
+    }
+
+    /// Sets the output latency, the time between audio being mixed and it
+    /// being heard.
+    ///
+    /// This is used to estimate [`AudioControl::position`]. If the mix isn't
+    /// played in real time, this should be `None`, and the position is the
+    /// timestamp of the audio.
+    pub fn set_latency(&mut self, latency: Option<Duration>) {
+        self.latency = latency;
+    }
+
+    /// Sets the gains of the buses.
+    pub fn set_buses(&self, buses: &AudioBuses) {
+        self.bus_gains.set(buses);
+    }
+
+    /// Returns the bus gains, shared with the mixer.
+    pub(super) fn bus_gains(&self) -> Arc<BusGains> {
+        self.bus_gains.clone()
+    }
+
+    /// Returns the analysis tap of the mix, shared with the mixer.
+    pub(super) fn master_tap(&self) -> Arc<AnalysisTap> {
+        self.master_tap.clone()
+    }
+
+    /// The number of voices currently playing.
+    pub fn len(&self) -> usize {
+        self.voices.len()
+    }
+
+    /// Checks if there are no voices playing.
+    pub fn is_empty(&self) -> bool {
+        self.voices.is_empty()
+    }
+
+    /// Mixes all voices into `buf`, filling it entirely.
+    pub fn mix(&mut self, buf: &mut [i16]) {
+        let len = buf.len();
+        let channels = self.channels as u64;
+
+        self.mix_buffer.clear();
+        self.mix_buffer.resize(len, 0.);
+
+        if self.voice_buffer.len() < len {
+            self.voice_buffer.resize(len, 0);
+        }
+
+        let sample_rate = self.sample_rate;
+        let smoothing = self.smoothing;
+        let latency = self.latency;
+        let now = Instant::now();
+
+        // find where scheduled voices start before any timeline advances
+        let frame_len = len / channels as usize;
+
+        for voice in self.voices.iter_mut() {
+            voice.start_offset = voice.find_start_offset(frame_len);
+        }
+
+        let Self {
+            voices,
+            bus_gains,
+            mix_buffer,
+            voice_buffer,
+            ..
+        } = self;
+
+        voices.retain_mut(|voice| {
+            if let Err(err) = voice.apply_commands(sample_rate) {
+                error!("voice dropped: {}", err);
+                voice.control.inner.fail(err);
+                return false;
+            }
+
+            if voice.control.inner.is_done() {
+                return false;
+            }
+
+            let offset = match (voice.start, voice.start_offset.take()) {
+                (None, _) => 0,
+                (Some(_), Some(StartOffset::Frames(frames))) => {
+                    voice.start = None;
+
+                    // the voice may have been paused while waiting
+                    if voice.control.state() == PlaybackState::Pending {
+                        voice.control.inner.set_state(PlaybackState::Playing);
+                    }
+
+                    voice.control.inner.start_crossfade();
+
+                    frames
+                }
+                (Some(_), Some(StartOffset::Cancel)) => {
+                    voice.control.inner.set_state(PlaybackState::Stopped);
+                    return false;
+                }
+                (Some(_), None) => return true,
+            };
+
+            if voice.control.state() == PlaybackState::Paused {
+                return true;
+            }
+
+            if let Some(latency) = latency {
+                let offset_time = Duration::from_secs_f64(offset as f64 / sample_rate as f64);
+
+                voice.control.inner.record_clock(AudibleClock {
+                    timestamp: voice.position as u64,
+                    time: now + offset_time,
+                    latency,
+                    rate: voice.control.rate(),
+                    floor: voice.seeked_to,
+                });
+            }
+
+            let offset = offset * channels as usize;
+
+            let read_len = match voice.source.sample(&mut voice_buffer[..len - offset]) {
+                Ok(len) => len,
+                Err(err) => {
+                    error!("voice dropped: {}", err);
+                    voice.control.inner.fail(err);
+                    return false;
+                }
+            };
+
+            voice
+                .control
+                .inner
+                .tap
+                .process(&voice_buffer[..read_len], channels as usize);
+
+            let target = target_gains(&voice.control, bus_gains.gain(voice.bus));
+            let frames = mix_buffer[offset..]
+                .chunks_mut(channels as usize)
+                .zip(voice_buffer[..read_len].chunks(channels as usize));
+            let mut peak = 0f32;
+
+            for (mix_frame, frame) in frames {
+                // smooth gain changes
+                for (gain, target) in voice.gains.iter_mut().zip(target) {
+                    *gain += (target - *gain) * smoothing;
+                }
+
+                if let [left, right] = frame {
+                    let left = left.to_sample::<f32>() * voice.gains[0];
+                    let right = right.to_sample::<f32>() * voice.gains[1];
+
+                    mix_frame[0] += left;
+                    mix_frame[1] += right;
+                    peak = peak.max(left.abs()).max(right.abs());
+                } else {
+                    // pan only makes sense in stereo
+                    let gain = (voice.gains[0] + voice.gains[1]) / 2.;
+
+                    for (mix, sample) in mix_frame.iter_mut().zip(frame) {
+                        let sample = sample.to_sample::<f32>() * gain;
+
+                        *mix += sample;
+                        peak = peak.max(sample.abs());
+                    }
+                }
+            }
+
+            // for meters and ducking
+            voice.control.inner.level.store(peak);
+
+            if voice.control.inner.is_faded_out() {
+                voice.control.inner.set_state(PlaybackState::Stopped);
+                return false;
+            }
+
+            // count mix len as samples, scaled by the rate they were played at
+            let samples = (read_len as u64 / channels) as f64;
+            voice.position += samples * voice.control.rate() as f64;
+
+            let timestamp = voice.position as u64;
+            voice
+                .control
+                .inner
+                .timestamp
+                .store(timestamp, Ordering::Release);
+
+            if read_len > 0 {
+                true
+            } else {
+                voice.control.inner.set_state(PlaybackState::Finished);
+                false
+            }
+        });
+
+        for (out, mix) in buf.iter_mut().zip(mix_buffer.iter()) {
+            *out = soft_clip(*mix).to_sample::<i16>();
+        }
+
+        self.master_tap.process(buf, channels as usize);
+    }
+}
+
+/// Where a scheduled voice starts in the next mix.
+enum StartOffset {
+    /// The voice starts this many frames in.
+    Frames(usize),
+    /// The timeline is done, so the voice never starts.
+    Cancel,
+}
+
+impl Voice {
+    /// Finds where the voice starts in a mix of `frame_len` frames.
+    ///
+    /// Returns `None` if the voice doesn't start in this mix.
+    fn find_start_offset(&self, frame_len: usize) -> Option<StartOffset> {
+        let start = self.start?;
+        let timeline = &self.control.inner.schedule.as_ref()?.timeline;
+
+        match timeline.state() {
+            PlaybackState::Playing => {
+                let timestamp = timeline.timestamp.load(Ordering::Acquire);
+
+                // late voices start right away
+                let samples = start.saturating_sub(timestamp);
+                let frames = (samples as f64 / timeline.rate.load() as f64).ceil() as usize;
+
+                (frames < frame_len).then_some(StartOffset::Frames(frames))
+            }
+            PlaybackState::Pending | PlaybackState::Paused => None,
+            _ => Some(StartOffset::Cancel),
+        }
+    }
+
+    /// Applies the commands sent from the voice's [`AudioControl`].
+    fn apply_commands(&mut self, sample_rate: u32) -> Result<(), BoxedError> {
+        let Voice {
+            source,
+            control,
+            position: voice_position,
+            seeked_to,
+            start,
+            ..
+        } = self;
+        let mut result = Ok(());
+
+        control.inner.drain_commands(|command| {
+            let state = &control.inner;
+
+            if state.is_done() {
+                // stopped voices stay stopped
+                return;
+            }
+
+            match command {
+                AudioCommand::Pause => {
+                    state.freeze_clock(Instant::now(), sample_rate);
+                    state.set_state(PlaybackState::Paused);
+                }
+                // a voice still waiting for its start goes back to waiting
+                AudioCommand::Resume if state.state() == PlaybackState::Paused => {
+                    state.set_state(match start {
+                        Some(_) => PlaybackState::Pending,
+                        None => PlaybackState::Playing,
+                    });
+                }
+                AudioCommand::Resume => (),
+                AudioCommand::Stop => state.set_state(PlaybackState::Stopped),
+                AudioCommand::Seek(duration) => {
+                    let position = duration_to_samples(duration, sample_rate);
+
+                    match source.seek(position as usize) {
+                        Ok(()) => {
+                            *voice_position = position as f64;
+                            *seeked_to = position;
+                            state.timestamp.store(position, Ordering::Release);
+                        }
+                        Err(err) => result = Err(err),
+                    }
+
+                    state.finish_seek(duration);
+                }
+                AudioCommand::Fade {
+                    from,
+                    to,
+                    duration,
+                    curve,
+                    stop,
+                } => state.request_fade(from, to, duration, curve, stop),
+            }
+        });
+
+        result
+    }
+}
+
+impl Source for Mixer {
+    type Error = Infallible;
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> u8 {
+        self.channels
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        self.mix(buf);
+
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, _position: usize) -> Result<(), Self::Error> {
+        // voices are seeked through their own controls
+        Ok(())
+    }
+}
+
+/// Returns the gains of the left and right channels of a voice.
+fn target_gains(control: &AudioControl, bus_gain: f32) -> [f32; 2] {
+    let gain = control.volume() * control.inner.normalization.load() * bus_gain;
+    let pan = control.pan();
+
+    // balance law; the centre is at unity
+    let left = (1. - pan).min(1.);
+    let right = (1. + pan).min(1.);
+
+    [left * gain, right * gain]
+}
+
+/// Converts a [`Duration`] to a count of samples at `sample_rate`.
+fn duration_to_samples(duration: Duration, sample_rate: u32) -> u64 {
+    (duration.as_nanos() * sample_rate as u128 / 1_000_000_000) as u64
+}
+
+/// Limits a sample to `[-1, 1]`, compressing anything over
+/// [`SOFT_CLIP_THRESHOLD`] instead of hard clipping it.
+fn soft_clip(sample: f32) -> f32 {
+    let level = sample.abs();
+
+    if level <= SOFT_CLIP_THRESHOLD {
+        sample
+    } else {
+        let headroom = 1. - SOFT_CLIP_THRESHOLD;
+        let over = (level - SOFT_CLIP_THRESHOLD) / headroom;
+
+        sample.signum() * (SOFT_CLIP_THRESHOLD + headroom * over.tanh())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::fixtures::wav;
+    use crate::audio::source::WavDecoder;
+
+    const SAMPLE_RATE: u32 = 48_000;
+
+    fn silence(frames: usize) -> BoxedSource {
+        WavDecoder::new(wav(2, SAMPLE_RATE, &vec![0; frames * 2]))
+            .unwrap()
+            .boxed()
+    }
+
+    #[test]
+    fn scheduled_voice_waits_for_its_start_when_resumed() {
+        let mut mixer = Mixer::new(SAMPLE_RATE, 2);
+        let mut buf = [0; 2 * 480];
+
+        let timeline = AudioControl::default();
+        mixer.add(silence(48_000), timeline.clone(), AudioBus::Music);
+
+        let actl = AudioControl::scheduled(&timeline, Duration::from_millis(100));
+        mixer.add(silence(48_000), actl.clone(), AudioBus::Music);
+
+        actl.resume();
+        mixer.mix(&mut buf);
+
+        assert_eq!(actl.state(), PlaybackState::Pending);
+        assert_eq!(actl.timestamp(), 0);
+
+        actl.pause();
+        mixer.mix(&mut buf);
+
+        assert_eq!(actl.state(), PlaybackState::Paused);
+
+        actl.resume();
+        mixer.mix(&mut buf);
+
+        assert_eq!(actl.state(), PlaybackState::Pending);
+        assert_eq!(actl.timestamp(), 0);
+
+        // 100ms in, the voice starts on time
+        for _ in 0..8 {
+            mixer.mix(&mut buf);
+        }
+
+        assert_eq!(actl.state(), PlaybackState::Playing);
+        assert_eq!(actl.timestamp(), 480);
+    }
+
+    #[test]
+    fn resume_only_resumes_paused_voices() {
+        let mut mixer = Mixer::new(SAMPLE_RATE, 2);
+        let mut buf = [0; 2 * 480];
+
+        let actl = AudioControl::default();
+        mixer.add(silence(48_000), actl.clone(), AudioBus::Music);
+
+        actl.pause();
+        mixer.mix(&mut buf);
+        assert_eq!(actl.state(), PlaybackState::Paused);
+
+        actl.resume();
+        mixer.mix(&mut buf);
+        assert_eq!(actl.state(), PlaybackState::Playing);
+
+        actl.stop();
+        actl.resume();
+        mixer.mix(&mut buf);
+        assert_eq!(actl.state(), PlaybackState::Stopped);
+    }
+}
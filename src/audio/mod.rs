@@ -1,25 +1,58 @@
 //! Custom audio solution for precise audio timings.
 
+mod analysis;
 mod asset;
+mod buffered;
+mod control;
+#[cfg(test)]
+mod fixtures;
+mod loudness;
+mod mixer;
+mod null;
+mod settings;
 pub mod source;
+mod waveform;
 
-pub use asset::{AudioLoader, AudioSource};
-use source::{OggDecoder, Resampler, Source};
+use analysis::AnalysisTap;
+pub use analysis::{AudioAnalysis, ANALYSIS_WINDOW};
+use asset::OutputRate;
+pub use asset::{
+    AudioFormat, AudioLoadMode, AudioLoader, AudioLoaderSettings, AudioMetadata, AudioSource,
+    DecodedSound, LoopRegion, Normalization, DECODED_LABEL, WAVEFORM_LABEL,
+};
+use buffered::{Buffered, DecodeWorker};
+pub use control::{AudioControl, PlaybackState, RateMode, MAX_RATE, MIN_RATE};
+pub use loudness::{Loudness, NORMALIZATION_TARGET};
+use mixer::BusGains;
+pub use mixer::{AudioBus, AudioBuses, Mixer};
+use null::NullOutput;
+pub use null::{NullAudio, NullClock};
+use settings::{load_audio_settings, open_output_device, save_audio_settings};
+pub use settings::{output_devices, AudioSettings, AUDIO_SETTINGS_PATH};
+use source::{
+    BoxedSource, ChannelConverter, DecodeError, DecodedSource, Decoder, Looped, Resampler, Source,
+};
+pub use source::{FadeCurve, FilterKind};
+pub use waveform::{Peak, Waveform, WaveformLevel, WAVEFORM_BASE_FRAMES};
 
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
+use bevy::utils::HashSet;
 
 use std::{
+    collections::VecDeque,
+    fmt::{self, Display, Formatter},
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, Ordering},
         mpsc::{channel, Receiver, Sender},
-        Arc,
+        Arc, OnceLock,
     },
     time::Duration,
 };
 
 use cpal::{
-    traits::{DeviceTrait, HostTrait, StreamTrait},
-    Device, Sample, SampleFormat, SampleRate, Stream, StreamConfig,
+    traits::{DeviceTrait, StreamTrait},
+    Device, FromSample, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig,
 };
 
 /// The desired sample rate.
@@ -28,18 +61,54 @@ pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
 /// The default channels of audio.
 pub const CHANNEL_COUNT: u16 = 2;
 
+/// How often to try opening an audio device while there is none.
+const DEVICE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Includes audio systems and components.
 pub struct AudioPlugin;
 
 impl Plugin for AudioPlugin {
     fn build(&self, app: &mut App) {
+        let output_rate = OutputRate::default();
+
         app.add_event::<TrackStart>()
+            .add_event::<TrackFinished>()
+            .add_event::<TrackError>()
             .init_asset::<AudioSource>()
-            .init_asset_loader::<AudioLoader>()
-            .init_non_send_resource::<AudioDevice>()
-            .add_systems(PreUpdate, send_sound_events)
-            .add_systems(Update, start_spawned_audio)
-            .add_systems(Startup, setup_sound_device);
+            .init_asset::<DecodedSound>()
+            .init_asset::<Waveform>()
+            .register_asset_loader(AudioLoader::new(output_rate.clone()))
+            .insert_non_send_resource(AudioDevice {
+                state: None,
+                output_rate,
+            })
+            .init_resource::<AudioBuses>()
+            .init_resource::<AudioSettings>()
+            .add_systems(First, drive_null_audio)
+            .add_systems(
+                PreUpdate,
+                (
+                    send_sound_events,
+                    update_master_analysis,
+                    update_voice_analysis,
+                ),
+            )
+            .add_systems(
+                Update,
+                (
+                    (
+                        maintain_sound_device,
+                        resample_decoded_sounds,
+                        start_spawned_audio::<AudioSource>,
+                        start_spawned_audio::<DecodedSound>,
+                        queue_spawned_audio,
+                    )
+                        .chain(),
+                    update_bus_gains.run_if(resource_changed::<AudioBuses>),
+                    save_audio_settings.run_if(settings_edited),
+                ),
+            )
+            .add_systems(Startup, (load_audio_settings, setup_sound_device).chain());
     }
 }
 
@@ -50,62 +119,194 @@ impl Plugin for AudioPlugin {
 pub struct AudioBundle {
     pub source: Handle<AudioSource>,
     pub actl: AudioControl,
+    pub bus: AudioBus,
 }
 
-/// A component for audio source control.
+/// Audio to play after the audio of this entity, without a gap.
 ///
-/// # Note
-/// The buffer for `cpal` on most platforms (including WASM, the platform this
-/// game will ship on!) is quite large. Controlling audio may have delays of
-/// multiple frames, and the [`AudioControl::timestamp`] function may record
-/// large jumps in timestamp.
-#[derive(Clone, Component)]
-pub struct AudioControl {
-    sample_rate: u32,
-    inner: Arc<AudioControlState>,
+/// Each source is queued on the entity's [`AudioControl`] as soon as it is
+/// loaded, so an intro and a loop split into two files join seamlessly:
+/// ```ignore
+/// commands.spawn((
+///     AudioBundle {
+///         source: asset_server.load("music/battle_intro.ogg"),
+///         ..Default::default()
+///     },
+///     AudioQueue::from([asset_server.load("music/battle_loop.ogg")]),
+/// ));
+/// ```
+/// A source must finish loading before the audio before it ends, or there
+/// will be a gap.
+///
+/// Sources stay in the queue after they are handed to the audio device, so
+/// if the device changes, the audio picks up in the source that was playing
+/// and the rest are queued again.
+#[derive(Clone, Component, Debug, Default, Deref, DerefMut)]
+pub struct AudioQueue {
+    /// The sources, in order, including the ones already queued.
+    #[deref]
+    pub sources: VecDeque<Handle<AudioSource>>,
+    /// How many of the sources have been handed to the audio device.
+    queued: usize,
 }
 
-impl AudioControl {
-    /// Returns the song position as a [`Duration`].
-    pub fn position(&self) -> Duration {
-        Duration::from_nanos(1_000_000_000 / self.sample_rate as u64) * self.timestamp() as u32
+impl<const N: usize> From<[Handle<AudioSource>; N]> for AudioQueue {
+    fn from(value: [Handle<AudioSource>; N]) -> Self {
+        AudioQueue {
+            sources: VecDeque::from(value),
+            queued: 0,
+        }
     }
+}
 
-    /// Returns the timestamp of the audio in samples.
-    pub fn timestamp(&self) -> u64 {
-        self.inner.timestamp.load(Ordering::Acquire)
-    }
+/// A bundle for playing a [`DecodedSound`].
+///
+/// When this is spawned, the sound will immediately begin playing. This
+/// starts much faster than an [`AudioBundle`], so it is suited to sounds that
+/// must play right away, like hitsounds.
+#[derive(Bundle, Default)]
+pub struct DecodedSoundBundle {
+    pub sound: Handle<DecodedSound>,
+    pub actl: AudioControl,
+    pub bus: AudioBus,
 }
 
-impl Default for AudioControl {
-    /// Creates an unheaded `AudioControl`.
-    fn default() -> Self {
-        AudioControl {
-            sample_rate: DEFAULT_SAMPLE_RATE,
-            inner: Arc::new(AudioControlState {
-                timestamp: AtomicU64::new(0),
-            }),
-        }
-    }
+/// Plays [`DecodedSound`]s without spawning entities.
+///
+/// Sounds played through this are sent to the audio device as soon as they
+/// are played, instead of on the next update like a [`DecodedSoundBundle`].
+/// This is the path for sounds responding to input, like hitsounds.
+///
+/// Since there is no entity to replay them from, these sounds are cut off if
+/// the audio device changes.
+#[derive(SystemParam)]
+pub struct SoundPlayer<'w> {
+    audio_device: NonSend<'w, AudioDevice>,
+    sounds: Res<'w, Assets<DecodedSound>>,
 }
 
-struct AudioControlState {
-    timestamp: AtomicU64,
+impl SoundPlayer<'_> {
+    /// Plays a sound on `bus`.
+    ///
+    /// Returns the control of the sound, or `None` if the sound isn't loaded
+    /// or couldn't be played.
+    pub fn play(&self, sound: &Handle<DecodedSound>, bus: AudioBus) -> Option<AudioControl> {
+        self.play_with(sound, bus, AudioControl::default())
+    }
+
+    /// Plays a sound on `bus` when `timeline` reaches `at`.
+    ///
+    /// See [`AudioControl::scheduled`].
+    pub fn play_at(
+        &self,
+        sound: &Handle<DecodedSound>,
+        bus: AudioBus,
+        timeline: &AudioControl,
+        at: Duration,
+    ) -> Option<AudioControl> {
+        self.play_with(sound, bus, AudioControl::scheduled(timeline, at))
+    }
+
+    fn play_with(
+        &self,
+        sound: &Handle<DecodedSound>,
+        bus: AudioBus,
+        mut actl: AudioControl,
+    ) -> Option<AudioControl> {
+        let sound = self.sounds.get(sound)?;
+
+        if let Some(sample_rate) = self.audio_device.sample_rate() {
+            actl.sample_rate = sample_rate;
+        }
+
+        match self.audio_device.play_decoded(sound.clone(), &actl, bus) {
+            Ok(()) => Some(actl),
+            Err(err) => {
+                error!("Failed to play sound: {}", err);
+                None
+            }
+        }
+    }
 }
 
 /// Marker component for loaded audio.
 #[derive(Clone, Copy, Component, Debug, Default)]
 pub struct LoadedAudio;
 
-#[derive(Default)]
 struct AudioDevice {
     state: Option<AudioState>,
+    /// The sample rate of the device, shared with the [`AudioLoader`].
+    output_rate: OutputRate,
 }
 
 struct AudioState {
-    _stream: Stream,
+    output: Output,
     streamer_options: StreamerOptions,
-    audio_queue: Sender<(OggDecoder, Arc<AudioControlState>)>,
+    device_name: String,
+    /// Set by the stream when the device errors or disconnects.
+    errored: Arc<AtomicBool>,
+    bus_gains: Arc<BusGains>,
+    master_tap: Arc<AnalysisTap>,
+    audio_queue: Sender<(BoxedSource, AudioControl, AudioBus)>,
+    /// The worker decoding streamed sources, started with the first one.
+    decode_worker: OnceLock<DecodeWorker>,
+}
+
+/// Where the mixed audio goes.
+enum Output {
+    /// A cpal stream; dropping this stops the stream.
+    Device(#[allow(dead_code)] Stream),
+    /// A simulated device.
+    Null(NullOutput),
+}
+
+impl AudioState {
+    /// Creates a source streaming `audio` at the rate and channels of the
+    /// mixer.
+    fn stream(&self, audio: AudioSource, ctl: &AudioControl) -> Result<BoxedSource, PlayError> {
+        // create decoder and state
+        let loop_region = audio.loop_region;
+        let decoder = Decoder::new(audio)?;
+
+        info!(
+            "got track, c = {}, sample_rate = {}",
+            decoder.channels(),
+            decoder.sample_rate(),
+        );
+
+        let order = decoder.channel_order();
+
+        // loop points are in samples of the file
+        let looped = Looped::new(decoder, loop_region, ctl.inner.loops.clone());
+
+        // convert to the mixer's channels before anything else
+        let converter = ChannelConverter::new(looped, CHANNEL_COUNT as u8, order);
+
+        // resample here so the streamer doesn't have to
+        let resampler = Resampler::new(converter, self.streamer_options.sample_rate)?;
+
+        if self.streamer_options.decode_thread {
+            let worker = match self.decode_worker.get() {
+                Some(worker) => worker,
+                None => {
+                    let worker = DecodeWorker::spawn().map_err(PlayError::Worker)?;
+                    self.decode_worker.get_or_init(|| worker)
+                }
+            };
+
+            let buffered =
+                Buffered::new(resampler.boxed(), ctl, worker).map_err(PlayError::Worker)?;
+
+            Ok(Box::new(buffered))
+        } else {
+            Ok(resampler.boxed())
+        }
+    }
+
+    /// Checks if this is playing on a simulated device.
+    fn is_null(&self) -> bool {
+        matches!(self.output, Output::Null(_))
+    }
 }
 
 impl AudioDevice {
@@ -119,9 +320,21 @@ impl AudioDevice {
         self.state.as_ref().map(|s| s.streamer_options.sample_rate)
     }
 
+    /// Sets the gains of every bus on the stream.
+    fn set_bus_gains(&self, buses: &AudioBuses) {
+        if let Some(state) = self.state.as_ref() {
+            state.bus_gains.set(buses);
+        }
+    }
+
     /// Initializes a cpal [`Device`] on this audio device.
+    ///
+    /// Anything playing on a previous device is dropped.
     #[allow(clippy::filter_next)] // disable lint for readability
     pub fn init(&mut self, device: Device) -> Result<(), String> {
+        // release the old device first
+        self.state = None;
+
         // find configs
         let supported_configs_range = match device.supported_output_configs() {
             Ok(s) => s,
@@ -131,23 +344,29 @@ impl AudioDevice {
         let desired_sample_rate = DEFAULT_SAMPLE_RATE;
 
         let mut supported_configs = supported_configs_range
-            .filter(|s| s.channels() == CHANNEL_COUNT)
-            .filter(|s| s.sample_format() == SampleFormat::I16)
+            .filter(|s| s.channels() > 0)
+            .filter(|s| sample_format_score(s.sample_format()).is_some())
             .map(|s| {
+                // prefer configs that need no remixing or conversion
+                let channel_score = u32::from(s.channels() != CHANNEL_COUNT);
+                let format_score = sample_format_score(s.sample_format()).unwrap_or(u32::MAX);
+
                 // score everything based on a distance from desired sample
                 // rate (44_100 or 48_000)
                 let min_sample_rate = s.min_sample_rate().0;
                 let max_sample_rate = s.max_sample_rate().0;
 
-                if desired_sample_rate < min_sample_rate {
-                    let score = min_sample_rate - desired_sample_rate;
-                    (s.with_sample_rate(SampleRate(min_sample_rate)), score)
+                let (sample_rate, rate_score) = if desired_sample_rate < min_sample_rate {
+                    (min_sample_rate, min_sample_rate - desired_sample_rate)
                 } else if desired_sample_rate > max_sample_rate {
-                    let score = min_sample_rate - desired_sample_rate;
-                    (s.with_sample_rate(SampleRate(max_sample_rate)), score)
+                    (max_sample_rate, desired_sample_rate - max_sample_rate)
                 } else {
-                    (s.with_sample_rate(SampleRate(desired_sample_rate)), 0)
-                }
+                    (desired_sample_rate, 0)
+                };
+
+                let score = (channel_score, rate_score, format_score);
+
+                (s.with_sample_rate(SampleRate(sample_rate)), score)
             })
             .collect::<Vec<_>>();
 
@@ -161,31 +380,42 @@ impl AudioDevice {
         }
 
         // pull first config
-        let config = Into::<StreamConfig>::into(
-            supported_configs
-                .into_iter()
-                .next()
-                .map(|(s, _)| s)
-                .ok_or_else(|| String::from("No valid audio device config found!"))?,
-        );
+        let supported_config = supported_configs
+            .into_iter()
+            .next()
+            .map(|(s, _)| s)
+            .ok_or_else(|| String::from("No valid audio device config found!"))?;
+        let sample_format = supported_config.sample_format();
+        let config = Into::<StreamConfig>::into(supported_config);
 
         let streamer_options = StreamerOptions {
-            channels: config.channels as u8,
             sample_rate: config.sample_rate.0,
+            decode_thread: true,
         };
 
-        let (audio_queue_tx, audio_queue_rx) = channel();
+        self.output_rate.set(streamer_options.sample_rate);
+
+        let (streamer, bus_gains, audio_queue) = Streamer::new(streamer_options.sample_rate);
+        let master_tap = streamer.mixer.master_tap();
+        let errored = Arc::new(AtomicBool::new(false));
 
         // build audio decoder thread
-        let stream = device
-            .build_output_stream(
-                &config,
-                audio_streamer(streamer_options.clone(), audio_queue_rx),
-                move |err| {
-                    error!("stream error: {}", err);
-                },
-                None,
-            )
+        let stream = match sample_format {
+            SampleFormat::I8 => build_stream::<i8>,
+            SampleFormat::I16 => build_stream::<i16>,
+            SampleFormat::I32 => build_stream::<i32>,
+            SampleFormat::I64 => build_stream::<i64>,
+            SampleFormat::U8 => build_stream::<u8>,
+            SampleFormat::U16 => build_stream::<u16>,
+            SampleFormat::U32 => build_stream::<u32>,
+            SampleFormat::U64 => build_stream::<u64>,
+            SampleFormat::F32 => build_stream::<f32>,
+            SampleFormat::F64 => build_stream::<f64>,
+            format => return Err(format!("unsupported sample format {}", format)),
+        };
+        let stream = stream(&device, &config, streamer, errored.clone());
+
+        let stream = stream
             .map_err(|e| format!("setup stream err: {}", e))
             .and_then(|s| match s.play() {
                 Ok(()) => Ok(s),
@@ -195,9 +425,14 @@ impl AudioDevice {
         match stream {
             Ok(stream) => {
                 self.state = Some(AudioState {
-                    _stream: stream,
+                    output: Output::Device(stream),
                     streamer_options,
-                    audio_queue: audio_queue_tx,
+                    device_name: device.name().unwrap_or_default(),
+                    errored,
+                    bus_gains,
+                    master_tap,
+                    audio_queue,
+                    decode_worker: OnceLock::new(),
                 });
 
                 Ok(())
@@ -206,119 +441,719 @@ impl AudioDevice {
         }
     }
 
+    /// Initializes a simulated device on this audio device.
+    ///
+    /// Anything playing on a previous device is dropped.
+    pub fn init_null(&mut self, null_audio: &NullAudio) {
+        let (streamer, bus_gains, audio_queue) = Streamer::new(null_audio.sample_rate);
+        let master_tap = streamer.mixer.master_tap();
+
+        self.output_rate.set(null_audio.sample_rate);
+
+        self.state = Some(AudioState {
+            output: Output::Null(NullOutput::new(streamer, null_audio)),
+            streamer_options: StreamerOptions {
+                sample_rate: null_audio.sample_rate,
+                // stepped audio should be deterministic
+                decode_thread: null_audio.clock == NullClock::WallClock,
+            },
+            device_name: String::from("null"),
+            errored: Arc::default(),
+            bus_gains,
+            master_tap,
+            audio_queue,
+            decode_worker: OnceLock::new(),
+        });
+    }
+
     /// Plays audio.
-    pub fn play(&self, audio: AudioSource, ctl: &AudioControl) -> Result<(), lewton::VorbisError> {
+    ///
+    /// The audio is mixed with anything else that is already playing, on
+    /// `bus`.
+    pub fn play(
+        &self,
+        audio: AudioSource,
+        ctl: &AudioControl,
+        bus: AudioBus,
+    ) -> Result<(), PlayError> {
         if let Some(state) = &self.state {
-            // create decoder and state
-            let decoder = OggDecoder::new(audio)?;
+            ctl.inner.normalization.store(audio.gain);
+
+            let source = state.stream(audio, ctl)?;
+
             // send song over
-            let _ = state.audio_queue.send((decoder, ctl.inner.clone()));
+            let _ = state
+                .audio_queue
+                .send((ctl.controlled(source), ctl.clone(), bus));
+        }
+
+        Ok(())
+    }
+
+    /// Queues audio to play right after the audio of `ctl`, without a gap.
+    ///
+    /// The queued audio shares `ctl`, so its timestamp carries on from where
+    /// the audio before it ended.
+    pub fn queue(&self, audio: AudioSource, ctl: &AudioControl) -> Result<(), PlayError> {
+        if let Some(state) = &self.state {
+            let gain = audio.gain;
+            let source = state.stream(audio, ctl)?;
+
+            if let Ok(mut queue) = ctl.inner.queue.lock() {
+                queue.push_back((source, gain));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Plays a decoded sound.
+    ///
+    /// The sound is mixed with anything else that is already playing, on
+    /// `bus`.
+    pub fn play_decoded(
+        &self,
+        sound: DecodedSound,
+        ctl: &AudioControl,
+        bus: AudioBus,
+    ) -> Result<(), PlayError> {
+        if let Some(state) = &self.state {
+            ctl.inner.normalization.store(sound.gain);
+
+            let loop_region = sound.loop_region;
+            let source = Looped::new(
+                DecodedSource::new(sound),
+                loop_region,
+                ctl.inner.loops.clone(),
+            );
+
+            // only if the sound hasn't been resampled to the device yet
+            let source = if source.sample_rate() == state.streamer_options.sample_rate {
+                source.boxed()
+            } else {
+                Resampler::new(source, state.streamer_options.sample_rate)?.boxed()
+            };
+
+            let source = ctl.controlled(source);
+
+            let _ = state.audio_queue.send((source, ctl.clone(), bus));
         }
 
         Ok(())
     }
 }
 
+/// An asset that can be played on an [`AudioDevice`].
+trait Playable: Asset + Clone {
+    fn play(
+        self,
+        audio_device: &AudioDevice,
+        ctl: &AudioControl,
+        bus: AudioBus,
+    ) -> Result<(), PlayError>;
+}
+
+impl Playable for AudioSource {
+    fn play(
+        self,
+        audio_device: &AudioDevice,
+        ctl: &AudioControl,
+        bus: AudioBus,
+    ) -> Result<(), PlayError> {
+        audio_device.play(self, ctl, bus)
+    }
+}
+
+impl Playable for DecodedSound {
+    fn play(
+        self,
+        audio_device: &AudioDevice,
+        ctl: &AudioControl,
+        bus: AudioBus,
+    ) -> Result<(), PlayError> {
+        audio_device.play_decoded(self, ctl, bus)
+    }
+}
+
 #[derive(Clone)]
 struct StreamerOptions {
     sample_rate: u32,
-    channels: u8,
+    /// Whether audio is decoded on a worker thread, instead of in the
+    /// streamer.
+    ///
+    /// Every streamed source of the device shares one worker; see
+    /// [`DecodeWorker`].
+    decode_thread: bool,
+}
+
+/// An error returned by [`AudioDevice::play`].
+#[derive(Debug)]
+pub enum PlayError {
+    Decode(DecodeError),
+    Resampler(rubato::ResamplerConstructionError),
+    Worker(std::io::Error),
+}
+
+impl From<DecodeError> for PlayError {
+    fn from(value: DecodeError) -> Self {
+        PlayError::Decode(value)
+    }
+}
+
+impl From<rubato::ResamplerConstructionError> for PlayError {
+    fn from(value: rubato::ResamplerConstructionError) -> Self {
+        PlayError::Resampler(value)
+    }
+}
+
+impl Display for PlayError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PlayError::Decode(err) => Display::fmt(err, f),
+            PlayError::Resampler(err) => Display::fmt(err, f),
+            PlayError::Worker(err) => write!(f, "failed to start decoder: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for PlayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PlayError::Decode(err) => Some(err),
+            PlayError::Resampler(err) => Some(err),
+            PlayError::Worker(err) => Some(err),
+        }
+    }
 }
 
 /// An event sent when the track starts.
 #[derive(Clone, Debug, Event)]
-pub struct TrackStart;
+pub struct TrackStart {
+    /// The entity of the track.
+    pub entity: Entity,
+}
 
-fn audio_streamer(
-    streamer_options: StreamerOptions,
-    audio_queue: Receiver<(OggDecoder, Arc<AudioControlState>)>,
-) -> impl FnMut(&mut [i16], &cpal::OutputCallbackInfo) + Send + 'static {
-    let mut source: Option<(Resampler<OggDecoder>, Arc<AudioControlState>)> = None;
-
-    move |data, _| {
-        if let Ok((decoder, actl)) = audio_queue.try_recv() {
-            info!(
-                "got track, c = {}, sample_rate = {}",
-                decoder.channels(),
-                decoder.sample_rate(),
-            );
-            // reset timestamp
-            actl.timestamp.store(0, Ordering::Release);
-            // load source onto player
-            let resampler = Resampler::new(decoder, streamer_options.sample_rate).unwrap();
-            source = Some((resampler, actl));
-        }
-
-        let mix_len = if let Some((decoder, actl)) = &mut source {
-            let mix_len = match decoder.sample(data) {
-                Ok(len) => len,
-                Err(err) => {
-                    error!("stream dropped: {}", err);
-                    0
-                }
-            };
+/// An event sent when the track plays to the end.
+///
+/// This is not sent for tracks stopped with [`AudioControl::stop`].
+#[derive(Clone, Debug, Event)]
+pub struct TrackFinished {
+    /// The entity of the track.
+    pub entity: Entity,
+}
 
-            // count mix len as samples
-            let samples = mix_len as u64 / CHANNEL_COUNT as u64;
-            actl.timestamp.fetch_add(samples, Ordering::AcqRel);
+/// An event sent when the track could not be played, or stopped because of
+/// an error.
+#[derive(Clone, Debug, Event)]
+pub struct TrackError {
+    /// The entity of the track.
+    pub entity: Entity,
+    /// A description of the error.
+    pub error: String,
+}
 
-            mix_len
-        } else {
-            0
+/// The last [`PlaybackState`] events were sent for.
+#[derive(Clone, Copy, Component, Debug, Default)]
+struct ObservedState(PlaybackState);
+
+/// Scores how well a device sample format fits the mixer, lower is better.
+///
+/// Returns `None` if the format isn't supported.
+fn sample_format_score(format: SampleFormat) -> Option<u32> {
+    match format {
+        SampleFormat::I16 => Some(0),
+        SampleFormat::F32 => Some(1),
+        SampleFormat::I32 | SampleFormat::F64 | SampleFormat::U16 => Some(2),
+        SampleFormat::I8 | SampleFormat::I64 | SampleFormat::U8 => Some(3),
+        SampleFormat::U32 | SampleFormat::U64 => Some(3),
+        _ => None,
+    }
+}
+
+/// Mixes queued audio into output buffers.
+struct Streamer {
+    mixer: Mixer,
+    audio_queue: Receiver<(BoxedSource, AudioControl, AudioBus)>,
+    mix_buffer: Vec<i16>,
+}
+
+impl Streamer {
+    /// Creates a new `Streamer`, returning the bus gains of its mixer and the
+    /// queue to send audio on.
+    fn new(
+        sample_rate: u32,
+    ) -> (
+        Streamer,
+        Arc<BusGains>,
+        Sender<(BoxedSource, AudioControl, AudioBus)>,
+    ) {
+        let (audio_queue_tx, audio_queue_rx) = channel();
+
+        // the mixer is always stereo, and remixed for the device
+        let mixer = Mixer::new(sample_rate, CHANNEL_COUNT as u8);
+        let bus_gains = mixer.bus_gains();
+
+        let streamer = Streamer {
+            mixer,
+            audio_queue: audio_queue_rx,
+            mix_buffer: Vec::new(),
         };
 
-        // fill the rest with silence
-        for data in data.iter_mut().skip(mix_len) {
-            *data = Sample::EQUILIBRIUM;
+        (streamer, bus_gains, audio_queue_tx)
+    }
+
+    /// Fills `data`, which has `channels` channels, with the mix.
+    ///
+    /// `latency` is the time until `data` is heard, if it is played in real
+    /// time.
+    fn stream<T>(&mut self, data: &mut [T], channels: usize, latency: Option<Duration>)
+    where
+        T: SizedSample + FromSample<i16>,
+    {
+        while let Ok((source, actl, bus)) = self.audio_queue.try_recv() {
+            // load source onto mixer
+            self.mixer.add(source, actl, bus);
+        }
+
+        self.mixer.set_latency(latency);
+
+        let frames = data.len() / channels;
+
+        self.mix_buffer.resize(frames * CHANNEL_COUNT as usize, 0);
+        self.mixer.mix(&mut self.mix_buffer);
+
+        remix(&self.mix_buffer, data, channels);
+    }
+}
+
+fn build_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    streamer: Streamer,
+    errored: Arc<AtomicBool>,
+) -> Result<Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<i16> + Send + 'static,
+{
+    device.build_output_stream(
+        config,
+        audio_streamer::<T>(streamer, config.channels as usize),
+        move |err| {
+            error!("stream error: {}", err);
+            errored.store(true, Ordering::Release);
+        },
+        None,
+    )
+}
+
+fn audio_streamer<T>(
+    mut streamer: Streamer,
+    channels: usize,
+) -> impl FnMut(&mut [T], &cpal::OutputCallbackInfo) + Send + 'static
+where
+    T: SizedSample + FromSample<i16> + Send + 'static,
+{
+    move |data, info| {
+        // how long until this buffer is heard
+        let timestamp = info.timestamp();
+        let latency = timestamp.playback.duration_since(&timestamp.callback);
+
+        streamer.stream(data, channels, Some(latency.unwrap_or_default()));
+    }
+}
+
+/// Remixes stereo samples into a buffer with `channels` channels, converting
+/// the samples along the way.
+///
+/// Stereo is averaged down to mono; anything more than stereo gets left and
+/// right in the first two channels, and silence in the rest.
+fn remix<T>(stereo: &[i16], out: &mut [T], channels: usize)
+where
+    T: SizedSample + FromSample<i16>,
+{
+    for (frame, out) in stereo.chunks_exact(2).zip(out.chunks_exact_mut(channels)) {
+        if channels == 1 {
+            let mono = (frame[0] as i32 + frame[1] as i32) / 2;
+            out[0] = T::from_sample(mono as i16);
+        } else {
+            out[0] = T::from_sample(frame[0]);
+            out[1] = T::from_sample(frame[1]);
+
+            for sample in out[2..].iter_mut() {
+                *sample = T::EQUILIBRIUM;
+            }
         }
     }
 }
 
 fn send_sound_events(
-    _audio_device: NonSendMut<AudioDevice>,
-    mut _track_start_tx: EventWriter<TrackStart>,
+    mut tracks: Query<(Entity, &AudioControl, &mut ObservedState)>,
+    mut track_start_tx: EventWriter<TrackStart>,
+    mut track_finished_tx: EventWriter<TrackFinished>,
+    mut track_error_tx: EventWriter<TrackError>,
 ) {
-    // TODO: impl
+    for (entity, actl, mut observed) in tracks.iter_mut() {
+        let state = actl.state();
+
+        if state == observed.0 {
+            continue;
+        }
+
+        // a short track may start and finish between frames
+        if observed.0 == PlaybackState::Pending && state != PlaybackState::Failed {
+            track_start_tx.send(TrackStart { entity });
+        }
+
+        match state {
+            PlaybackState::Finished => {
+                track_finished_tx.send(TrackFinished { entity });
+            }
+            PlaybackState::Failed => {
+                let error = actl.error().unwrap_or_default();
+                track_error_tx.send(TrackError { entity, error });
+            }
+            _ => (),
+        }
+
+        observed.0 = state;
+    }
 }
 
-fn start_spawned_audio(
-    mut query: Query<(Entity, &Handle<AudioSource>, &mut AudioControl), Without<LoadedAudio>>,
-    audio_sources: Res<Assets<AudioSource>>,
+fn start_spawned_audio<A: Playable>(
+    mut query: Query<
+        (Entity, &Handle<A>, &mut AudioControl, Option<&AudioBus>),
+        Without<LoadedAudio>,
+    >,
+    assets: Res<Assets<A>>,
     audio_device: NonSendMut<AudioDevice>,
     mut commands: Commands,
 ) {
-    for (entity, audio_source, mut actl) in query.iter_mut() {
-        if let Some(audio_source) = audio_sources.get(audio_source) {
+    for (entity, asset, mut actl, bus) in query.iter_mut() {
+        if let Some(asset) = assets.get(asset) {
             if let Some(state) = audio_device.state.as_ref() {
                 actl.sample_rate = state.streamer_options.sample_rate;
             }
 
             // start playing sound
-            if let Err(err) = audio_device.play(audio_source.clone(), &actl) {
+            let bus = bus.copied().unwrap_or_default();
+
+            if let Err(err) = asset.clone().play(&audio_device, &actl, bus) {
                 error!("Failed to play audio: {}", err);
+                actl.inner.fail(err);
             }
 
-            commands.entity(entity).insert(LoadedAudio);
+            commands
+                .entity(entity)
+                .insert((LoadedAudio, ObservedState::default()));
         }
     }
 }
 
-fn setup_sound_device(mut audio_device: NonSendMut<AudioDevice>) {
-    let host = cpal::default_host();
+/// Resamples sounds decoded at a different rate than the audio device, like
+/// sounds loaded before it was opened, so playing them never resamples.
+///
+/// Only sounds that were just loaded are checked, unless the rate of the
+/// device changed. A sound that fails to resample is left alone until it is
+/// loaded again.
+fn resample_decoded_sounds(
+    mut sounds: ResMut<Assets<DecodedSound>>,
+    mut events: EventReader<AssetEvent<DecodedSound>>,
+    mut last_rate: Local<Option<u32>>,
+    mut failed: Local<HashSet<AssetId<DecodedSound>>>,
+    audio_device: NonSend<AudioDevice>,
+) {
+    let mut changed = Vec::new();
 
-    let Some(device) = host.default_output_device() else {
-        warn!("No audio device found!");
+    for event in events.read() {
+        match *event {
+            AssetEvent::Added { id } | AssetEvent::Modified { id } => {
+                failed.remove(&id);
+                changed.push(id);
+            }
+            AssetEvent::Removed { id } => {
+                failed.remove(&id);
+            }
+            _ => (),
+        }
+    }
+
+    let Some(sample_rate) = audio_device.sample_rate() else {
         return;
     };
 
-    let name = device.name();
+    let stale = if *last_rate != Some(sample_rate) {
+        *last_rate = Some(sample_rate);
+        sounds.ids().collect()
+    } else {
+        changed
+    };
+
+    for id in stale {
+        let Some(sound) = sounds.get(id) else {
+            continue;
+        };
+
+        if sound.sample_rate == sample_rate || failed.contains(&id) {
+            continue;
+        }
+
+        match sound.resample(sample_rate) {
+            Ok(resampled) => sounds.insert(id, resampled),
+            Err(err) => {
+                error!("Failed to resample sound: {}", err);
+                failed.insert(id);
+            }
+        }
+    }
+}
+
+fn queue_spawned_audio(
+    mut query: Query<(&AudioControl, &mut AudioQueue), With<LoadedAudio>>,
+    audio_sources: Res<Assets<AudioSource>>,
+    audio_device: NonSend<AudioDevice>,
+) {
+    for (actl, mut queue) in query.iter_mut() {
+        // keep the order, even if a later source loads first
+        while let Some(audio) = queue
+            .sources
+            .get(queue.queued)
+            .and_then(|h| audio_sources.get(h))
+        {
+            if let Err(err) = audio_device.queue(audio.clone(), actl) {
+                error!("Failed to queue audio: {}", err);
+            }
+
+            queue.queued += 1;
+        }
+    }
+}
+
+fn update_master_analysis(
+    analysis: Option<ResMut<AudioAnalysis>>,
+    audio_device: NonSend<AudioDevice>,
+) {
+    let Some(state) = audio_device.state.as_ref() else {
+        return;
+    };
+
+    let sample_rate = state.streamer_options.sample_rate;
+
+    match analysis {
+        Some(mut analysis) => {
+            state.master_tap.set_enabled(true, sample_rate);
+            state.master_tap.read(&mut analysis);
+        }
+        None => state.master_tap.set_enabled(false, sample_rate),
+    }
+}
+
+fn update_voice_analysis(
+    mut voices: Query<(&AudioControl, &mut AudioAnalysis), With<LoadedAudio>>,
+    mut removed: RemovedComponents<AudioAnalysis>,
+    controls: Query<&AudioControl>,
+) {
+    for (actl, mut analysis) in voices.iter_mut() {
+        actl.inner.tap.set_enabled(true, actl.sample_rate);
+        actl.inner.tap.read(&mut analysis);
+    }
 
-    match audio_device.init(device) {
-        Ok(()) => {
+    for entity in removed.read() {
+        if let Ok(actl) = controls.get(entity) {
+            actl.inner.tap.set_enabled(false, actl.sample_rate);
+        }
+    }
+}
+
+fn update_bus_gains(buses: Res<AudioBuses>, audio_device: NonSend<AudioDevice>) {
+    audio_device.set_bus_gains(&buses);
+}
+
+fn setup_sound_device(
+    settings: Res<AudioSettings>,
+    buses: Res<AudioBuses>,
+    null_audio: Option<Res<NullAudio>>,
+    mut audio_device: NonSendMut<AudioDevice>,
+) {
+    if let Some(null_audio) = null_audio {
+        audio_device.init_null(&null_audio);
+        audio_device.set_bus_gains(&buses);
+        info!("Initialized null audio at {} Hz", null_audio.sample_rate);
+        return;
+    }
+
+    let device = open_output_device(settings.device.as_deref());
+
+    init_sound_device(&mut audio_device, device, &buses);
+}
+
+/// Only saves settings changed after they were loaded.
+fn settings_edited(settings: Res<AudioSettings>) -> bool {
+    settings.is_changed() && !settings.is_added()
+}
+
+/// Initializes `device` on `audio_device`, falling back to null audio if it
+/// fails.
+///
+/// The new stream starts with the gains of `buses`.
+fn init_sound_device(audio_device: &mut AudioDevice, device: Option<Device>, buses: &AudioBuses) {
+    let result = match device {
+        Some(device) => {
+            let name = device.name();
+            audio_device.init(device).map(|()| name)
+        }
+        None => Err(String::from("No audio device found!")),
+    };
+
+    match result {
+        Ok(name) => {
             info!("Successfuly initialized audio on device: {:?}", name);
         }
         Err(err) => {
             error!("got error initializing device stream: {}", err);
+            warn!("falling back to null audio");
+            audio_device.init_null(&NullAudio::default());
+        }
+    }
+
+    audio_device.set_bus_gains(buses);
+}
+
+fn drive_null_audio(
+    null_audio: Option<ResMut<NullAudio>>,
+    mut audio_device: NonSendMut<AudioDevice>,
+) {
+    let Some(AudioState {
+        output: Output::Null(output),
+        ..
+    }) = audio_device.state.as_mut()
+    else {
+        return;
+    };
+
+    let stepped = null_audio
+        .map(|mut null_audio| std::mem::take(&mut null_audio.pending))
+        .unwrap_or_default();
+
+    output.tick(stepped);
+}
+
+/// Switches the audio device when [`AudioSettings`] changes, and rebuilds the
+/// stream if the device errors or disconnects.
+///
+/// Tracks that were playing are resumed at their position on the new stream,
+/// in the source of their [`AudioQueue`] that was playing. Only audio spawned
+/// on an entity can be resumed: sounds played with a [`SoundPlayer`] are cut
+/// off.
+#[allow(clippy::too_many_arguments)]
+fn maintain_sound_device(
+    settings: Res<AudioSettings>,
+    buses: Res<AudioBuses>,
+    null_audio: Option<Res<NullAudio>>,
+    mut tracks: Query<
+        (
+            Option<&Handle<AudioSource>>,
+            Option<&Handle<DecodedSound>>,
+            &mut AudioControl,
+            Option<&AudioBus>,
+            Option<&mut AudioQueue>,
+        ),
+        With<LoadedAudio>,
+    >,
+    audio_sources: Res<Assets<AudioSource>>,
+    sounds: Res<Assets<DecodedSound>>,
+    mut audio_device: NonSendMut<AudioDevice>,
+    time: Res<Time<Real>>,
+    mut last_retry: Local<Duration>,
+) {
+    if null_audio.is_some() {
+        // null audio was asked for, there is no device to maintain
+        return;
+    }
+
+    let retry = settings.is_changed() || time.elapsed() - *last_retry >= DEVICE_RETRY_INTERVAL;
+
+    let rebuild = match audio_device.state.as_ref() {
+        Some(state) if state.errored.load(Ordering::Acquire) => {
+            warn!(
+                "lost audio device {:?}, rebuilding stream",
+                state.device_name
+            );
+            true
+        }
+        // keep trying until a device shows up
+        Some(state) if state.is_null() => retry,
+        Some(_) => settings.is_changed() && !settings.is_added(),
+        None => retry,
+    };
+
+    if !rebuild {
+        return;
+    }
+
+    *last_retry = time.elapsed();
+
+    let device = open_output_device(settings.device.as_deref());
+
+    if device.is_none() && audio_device.state.as_ref().is_some_and(AudioState::is_null) {
+        // still nothing, keep playing on null audio
+        return;
+    }
+
+    init_sound_device(&mut audio_device, device, &buses);
+
+    let sample_rate = audio_device.sample_rate().unwrap_or(DEFAULT_SAMPLE_RATE);
+
+    for (audio_source, sound, mut actl, bus, queue) in tracks.iter_mut() {
+        // pick up in the source that was playing; the rest are queued again
+        let played = actl.inner.played_sources.load(Ordering::Acquire);
+        let audio_source = match queue {
+            Some(mut queue) => {
+                queue.queued = played;
+
+                match played.checked_sub(1) {
+                    Some(i) => queue.sources.get(i).cloned(),
+                    None => audio_source.cloned(),
+                }
+            }
+            None => audio_source.cloned(),
+        };
+
+        let audio_source = audio_source.and_then(|h| audio_sources.get(&h));
+        let sound = sound.and_then(|h| sounds.get(h));
+
+        if actl.inner.is_done() {
+            continue;
+        }
+
+        let position = actl.position();
+        let paused = actl.state() == PlaybackState::Paused;
+
+        // queued sources were resampled for the old stream
+        if let Ok(mut queue) = actl.inner.queue.lock() {
+            queue.clear();
+        }
+
+        actl.sample_rate = sample_rate;
+
+        let bus = bus.copied().unwrap_or_default();
+
+        let result = match (audio_source, sound) {
+            (Some(audio_source), _) => audio_source.clone().play(&audio_device, &actl, bus),
+            (None, Some(sound)) => sound.clone().play(&audio_device, &actl, bus),
+            (None, None) => continue,
+        };
+
+        if let Err(err) = result {
+            error!("Failed to resume audio: {}", err);
+            actl.inner.fail(err);
+            continue;
+        }
+
+        // commands are applied after the voice is added to the mixer
+        actl.seek(position);
+
+        if paused {
+            actl.pause();
         }
     }
 }
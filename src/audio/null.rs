@@ -0,0 +1,116 @@
+//! A headless audio backend for machines without a sound card.
+
+use bevy::prelude::*;
+
+use std::time::{Duration, Instant};
+
+use super::{Streamer, CHANNEL_COUNT, DEFAULT_SAMPLE_RATE};
+
+/// The most frames mixed at once by the null backend.
+const NULL_BLOCK_FRAMES: usize = 1024;
+
+/// Plays audio without a sound card.
+///
+/// Inserting this resource before the [`AudioPlugin`] starts makes audio play
+/// on a simulated device instead of a real one. Sources are still pulled at
+/// [`NullAudio::sample_rate`], so [`AudioControl`] timestamps advance as they
+/// would on a real device. This is also used as a fallback when no audio
+/// device can be opened.
+///
+/// [`AudioPlugin`]: super::AudioPlugin
+/// [`AudioControl`]: super::AudioControl
+#[derive(Clone, Debug, Resource)]
+pub struct NullAudio {
+    /// The simulated sample rate.
+    pub sample_rate: u32,
+    /// What drives the simulated device.
+    pub clock: NullClock,
+    pub(super) pending: Duration,
+}
+
+/// What drives a [`NullAudio`] device.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NullClock {
+    /// Audio is played in real time.
+    #[default]
+    WallClock,
+    /// Audio is only played by [`NullAudio::step`], which is deterministic.
+    Manual,
+}
+
+impl NullAudio {
+    /// Creates a new `NullAudio`.
+    pub fn new(sample_rate: u32, clock: NullClock) -> NullAudio {
+        NullAudio {
+            sample_rate,
+            clock,
+            pending: Duration::ZERO,
+        }
+    }
+
+    /// Plays `duration` worth of audio on the next update.
+    ///
+    /// This only does anything with [`NullClock::Manual`].
+    pub fn step(&mut self, duration: Duration) {
+        self.pending += duration;
+    }
+}
+
+impl Default for NullAudio {
+    fn default() -> Self {
+        NullAudio::new(DEFAULT_SAMPLE_RATE, NullClock::WallClock)
+    }
+}
+
+/// The output of a [`NullAudio`] device.
+pub(super) struct NullOutput {
+    streamer: Streamer,
+    sample_rate: u32,
+    clock: NullClock,
+    last_tick: Instant,
+    /// Fractional frames carried over between ticks.
+    remainder: f64,
+    buffer: Vec<i16>,
+}
+
+impl NullOutput {
+    /// Creates a new `NullOutput`.
+    pub fn new(streamer: Streamer, null_audio: &NullAudio) -> NullOutput {
+        NullOutput {
+            streamer,
+            sample_rate: null_audio.sample_rate,
+            clock: null_audio.clock,
+            last_tick: Instant::now(),
+            remainder: 0.,
+            buffer: vec![0; NULL_BLOCK_FRAMES * CHANNEL_COUNT as usize],
+        }
+    }
+
+    /// Plays audio up to now, or the `stepped` duration if the clock is
+    /// [`NullClock::Manual`].
+    pub fn tick(&mut self, stepped: Duration) {
+        let now = Instant::now();
+
+        let (elapsed, latency) = match self.clock {
+            NullClock::WallClock => (now - self.last_tick, Some(Duration::ZERO)),
+            // without a latency, positions are exactly the timestamps
+            NullClock::Manual => (stepped, None),
+        };
+
+        self.last_tick = now;
+
+        let frames = elapsed.as_secs_f64() * self.sample_rate as f64 + self.remainder;
+        let mut frames_left = frames as usize;
+
+        self.remainder = frames.fract();
+
+        while frames_left > 0 {
+            let block_frames = frames_left.min(NULL_BLOCK_FRAMES);
+            let block = &mut self.buffer[..block_frames * CHANNEL_COUNT as usize];
+
+            self.streamer.stream(block, CHANNEL_COUNT as usize, latency);
+
+            frames_left -= block_frames;
+        }
+    }
+}
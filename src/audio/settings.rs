@@ -0,0 +1,96 @@
+//! Audio device selection and persistent audio settings.
+
+use bevy::prelude::*;
+
+use cpal::{
+    traits::{DeviceTrait, HostTrait},
+    Device,
+};
+
+use serde::{Deserialize, Serialize};
+
+use std::{fs, io};
+
+/// The file audio settings are persisted to.
+pub const AUDIO_SETTINGS_PATH: &str = "audio_settings.ron";
+
+/// Persistent audio settings.
+///
+/// A settings screen can freely change this resource. Changing the device
+/// switches the audio device automatically, and every change is saved to
+/// [`AUDIO_SETTINGS_PATH`].
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Resource, Serialize)]
+pub struct AudioSettings {
+    /// The name of the output device to play on, as listed by
+    /// [`output_devices`].
+    ///
+    /// If this is `None`, or the device can't be found, the default output
+    /// device of the system is used.
+    pub device: Option<String>,
+}
+
+impl AudioSettings {
+    /// Loads the settings from [`AUDIO_SETTINGS_PATH`].
+    pub fn load() -> io::Result<AudioSettings> {
+        let contents = fs::read_to_string(AUDIO_SETTINGS_PATH)?;
+
+        ron::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Saves the settings to [`AUDIO_SETTINGS_PATH`].
+    pub fn save(&self) -> io::Result<()> {
+        let contents = ron::ser::to_string_pretty(self, Default::default())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        fs::write(AUDIO_SETTINGS_PATH, contents)
+    }
+}
+
+/// Lists the names of all output devices.
+pub fn output_devices() -> Vec<String> {
+    let host = cpal::default_host();
+
+    match host.output_devices() {
+        Ok(devices) => devices.filter_map(|d| d.name().ok()).collect(),
+        Err(err) => {
+            error!("failed to list audio devices: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+/// Opens the output device named `name`.
+///
+/// Falls back to the default output device if `name` is `None` or the device
+/// can't be found.
+pub(super) fn open_output_device(name: Option<&str>) -> Option<Device> {
+    let host = cpal::default_host();
+
+    if let Some(name) = name {
+        let device = host
+            .output_devices()
+            .ok()
+            .and_then(|mut devices| devices.find(|d| d.name().is_ok_and(|n| n == name)));
+
+        match device {
+            Some(device) => return Some(device),
+            None => warn!("audio device {:?} not found, using default", name),
+        }
+    }
+
+    host.default_output_device()
+}
+
+pub(super) fn load_audio_settings(mut settings: ResMut<AudioSettings>) {
+    match AudioSettings::load() {
+        Ok(loaded) => *settings = loaded,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => warn!("failed to load audio settings: {}", err),
+    }
+}
+
+pub(super) fn save_audio_settings(settings: Res<AudioSettings>) {
+    if let Err(err) = settings.save() {
+        warn!("failed to save audio settings: {}", err);
+    }
+}
@@ -1,12 +1,22 @@
 //! Lower level audio processing types and traits.
 
 use std::cmp::min;
+use std::convert::Infallible;
+use std::f32::consts::{FRAC_1_SQRT_2, FRAC_PI_2};
 use std::fmt::{self, Debug, Display, Formatter};
-use std::io::Cursor;
+use std::fs::File;
+use std::io::{self, BufWriter, Cursor, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use std::time::Duration;
 
-use super::asset::AudioSource;
+use super::asset::{AudioFormat, AudioSource, DecodedSound, LoopRegion};
 
+use claxon::{frame::FrameReader, metadata::StreamInfo, FlacReader};
 use dasp_sample::Sample;
 use lewton::inside_ogg::OggStreamReader;
 use rubato::{FftFixedIn, Resampler as _};
@@ -42,6 +52,75 @@ pub trait Source {
     /// Seeks the audio source for a specific position in samples on a
     /// per-channel basis.
     fn seek(&mut self, position: usize) -> Result<(), Self::Error>;
+
+    /// Erases the type of the source, boxing it into a [`BoxedSource`].
+    ///
+    /// This is how differently typed sources are handed to the
+    /// [`Mixer`](super::Mixer).
+    fn boxed(self) -> BoxedSource
+    where
+        Self: Sized + Send + 'static,
+        Self::Error: std::error::Error + Send + Sync + 'static,
+    {
+        Box::new(ErasedSource(self))
+    }
+}
+
+/// A boxed error returned by a [`BoxedSource`].
+pub type BoxedError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A type-erased [`Source`].
+///
+/// See [`Source::boxed`].
+pub type BoxedSource = Box<dyn Source<Error = BoxedError> + Send>;
+
+impl<T> Source for Box<T>
+where
+    T: Source + ?Sized,
+{
+    type Error = T::Error;
+
+    fn sample_rate(&self) -> u32 {
+        (**self).sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        (**self).channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        (**self).sample(buf)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        (**self).seek(position)
+    }
+}
+
+struct ErasedSource<T>(T);
+
+impl<T> Source for ErasedSource<T>
+where
+    T: Source,
+    T::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Error = BoxedError;
+
+    fn sample_rate(&self) -> u32 {
+        self.0.sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        self.0.channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        self.0.sample(buf).map_err(Into::into)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        self.0.seek(position).map_err(Into::into)
+    }
 }
 
 /// A thin wrapper over a [`Source`] that counts position in samples.
@@ -116,6 +195,9 @@ where
 /// This struct intelligently acts as a passthrough in the case that `from` ==
 /// `to`.
 ///
+/// The delay of the resampler is skipped, and the end of the audio is
+/// flushed out with silence, so every sample stays where it was in time.
+///
 /// [1]: https://github.com/HEnquist/rubato
 pub struct Resampler<T> {
     inner: T,
@@ -130,6 +212,16 @@ struct ResamplerState {
     to_buffer: Vec<Vec<f32>>,
     to_buffer_rem: usize,
     fft: FftFixedIn<f32>,
+    from: u64,
+    to: u64,
+    /// The output frames left to skip for the delay of the resampler.
+    delay: usize,
+    /// The input frames resampled since the start or the last seek.
+    frames_in: u64,
+    /// The output frames produced since the start or the last seek.
+    frames_out: u64,
+    /// Whether the inner source is exhausted, and silence is being fed in.
+    flushing: bool,
     eof: bool,
 }
 
@@ -180,6 +272,12 @@ where
                 from_buffer,
                 to_buffer: fft.output_buffer_allocate(true),
                 to_buffer_rem: 0,
+                from: from as u64,
+                to: to as u64,
+                delay: fft.output_delay(),
+                frames_in: 0,
+                frames_out: 0,
+                flushing: false,
                 fft,
                 eof: false,
             })
@@ -214,6 +312,11 @@ where
                 let channels = inner.channels() as usize;
                 let len = next_chunk(&mut buf[buf_cursor..], inner, state, channels)?;
 
+                if len == 0 && state.eof {
+                    // the inner source is exhausted
+                    break;
+                }
+
                 buf_cursor += len;
             }
 
@@ -224,15 +327,43 @@ where
     }
 
     fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
-        if let Some(_state) = self.state.as_mut() {
-            // reset resampler to avoid weird interpolation
-            todo!()
+        if let Some(state) = self.state.as_mut() {
+            // `position` is at our sample rate, convert to the inner rate
+            let from = self.inner.sample_rate() as u64;
+            let inner_position = position as u64 * from / self.to as u64;
+
+            self.inner.seek(inner_position as usize)?;
+
+            // reset resampler to avoid weird interpolation; the delay is
+            // skipped again, so the seek lands on `position`
+            state.reset();
+
+            Ok(())
         } else {
             self.inner.seek(position).map_err(Into::into)
         }
     }
 }
 
+impl ResamplerState {
+    /// Flushes all buffered samples and the internal state of the resampler.
+    fn reset(&mut self) {
+        self.fft.reset();
+
+        for buffer in self.from_buffer.iter_mut() {
+            buffer.clear();
+        }
+
+        self.to_buffer_rem = 0;
+        // the delay comes back with the silence the resampler starts from
+        self.delay = self.fft.output_delay();
+        self.frames_in = 0;
+        self.frames_out = 0;
+        self.flushing = false;
+        self.eof = false;
+    }
+}
+
 fn next_chunk<T>(
     buf: &mut [i16],
     inner: &mut T,
@@ -244,20 +375,20 @@ where
 {
     // My hope is this code is so terrible I am never allowed to write DSP code
     // ever again.
-    if state.eof {
-        return Ok(0);
-    }
-
     if state.to_buffer_rem > 0 {
         // use up contained buffer stuff before doing more processing
         return Ok(consume_buffer(buf, state));
     }
 
+    if state.eof {
+        return Ok(0);
+    }
+
     // what is next required for the next resample?
     let requested_len = state.fft.input_frames_next();
     let mut have_len = state.from_buffer[0].len();
 
-    while have_len < requested_len {
+    while have_len < requested_len && !state.flushing {
         // fill buffer with samples from inner
         let read_len = inner.sample(&mut state.from_buffer_itl)?;
 
@@ -270,7 +401,7 @@ where
             have_len += read_len;
         } else {
             // break! we do not have enough data left in the stream
-            break;
+            state.flushing = true;
         }
     }
 
@@ -281,20 +412,47 @@ where
             .process_into_buffer(&state.from_buffer, &mut state.to_buffer, None)
             .map_err(ResampleError::Resample)?
     } else {
-        // we are at the end!
-        state.eof = true;
+        // we are at the end! pad with silence to flush out the delay
+        let wave_in = (have_len > 0).then_some(state.from_buffer.as_slice());
 
         state
             .fft
-            .process_partial_into_buffer(Some(&state.from_buffer), &mut state.to_buffer, None)
+            .process_partial_into_buffer(wave_in, &mut state.to_buffer, None)
             .map_err(ResampleError::Resample)?
     };
 
     // drain processed samples
+    let in_len = min(in_len, have_len);
+
     for buffer in state.from_buffer.iter_mut() {
         buffer.drain(0..in_len);
     }
 
+    state.frames_in += in_len as u64;
+
+    // skip the delay, so the output lines up with the input
+    let skip = min(state.delay, out_len);
+    let mut out_len = out_len - skip;
+
+    state.delay -= skip;
+
+    for buffer in state.to_buffer.iter_mut() {
+        buffer.rotate_left(skip);
+    }
+
+    if state.flushing {
+        // stop once every input frame has come out
+        let expected = (state.frames_in * state.to).div_ceil(state.from);
+        let left = expected.saturating_sub(state.frames_out) as usize;
+
+        if out_len >= left {
+            out_len = left;
+            state.eof = true;
+        }
+    }
+
+    state.frames_out += out_len as u64;
+
     // `out_len` is in frames and we need to convert to total samples
     state.to_buffer_rem = out_len * channels;
 
@@ -385,78 +543,2498 @@ where
     }
 }
 
-/// An `.ogg` decoder for an audio asset.
-pub struct OggDecoder {
+/// The order of channels in multichannel audio.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ChannelOrder {
+    /// The order used by Vorbis, where the centre follows front left.
+    Vorbis,
+    /// The order used by `.wav` and `.flac`, where the centre follows front
+    /// right.
+    #[default]
+    Smpte,
+}
+
+/// A speaker position, for downmixing.
+#[derive(Clone, Copy, Debug)]
+enum Speaker {
+    Mono,
+    FrontLeft,
+    FrontRight,
+    FrontCentre,
+    Lfe,
+    BackLeft,
+    BackRight,
+    BackCentre,
+    SideLeft,
+    SideRight,
+}
+
+impl Speaker {
+    /// Returns the speakers of each channel for a channel count.
+    fn layout(channels: u8, order: ChannelOrder) -> Vec<Speaker> {
+        use Speaker::*;
+
+        match (channels, order) {
+            (1, _) => vec![Mono],
+            (2, _) => vec![FrontLeft, FrontRight],
+            (3, ChannelOrder::Vorbis) => vec![FrontLeft, FrontCentre, FrontRight],
+            (3, ChannelOrder::Smpte) => vec![FrontLeft, FrontRight, FrontCentre],
+            (4, _) => vec![FrontLeft, FrontRight, BackLeft, BackRight],
+            (5, ChannelOrder::Vorbis) => {
+                vec![FrontLeft, FrontCentre, FrontRight, BackLeft, BackRight]
+            }
+            (5, ChannelOrder::Smpte) => {
+                vec![FrontLeft, FrontRight, FrontCentre, BackLeft, BackRight]
+            }
+            (6, ChannelOrder::Vorbis) => {
+                vec![FrontLeft, FrontCentre, FrontRight, BackLeft, BackRight, Lfe]
+            }
+            (6, ChannelOrder::Smpte) => {
+                vec![FrontLeft, FrontRight, FrontCentre, Lfe, BackLeft, BackRight]
+            }
+            (7, ChannelOrder::Vorbis) => vec![
+                FrontLeft,
+                FrontCentre,
+                FrontRight,
+                SideLeft,
+                SideRight,
+                BackCentre,
+                Lfe,
+            ],
+            (7, ChannelOrder::Smpte) => vec![
+                FrontLeft,
+                FrontRight,
+                FrontCentre,
+                Lfe,
+                BackCentre,
+                SideLeft,
+                SideRight,
+            ],
+            (8, ChannelOrder::Vorbis) => vec![
+                FrontLeft,
+                FrontCentre,
+                FrontRight,
+                SideLeft,
+                SideRight,
+                BackLeft,
+                BackRight,
+                Lfe,
+            ],
+            (8, ChannelOrder::Smpte) => vec![
+                FrontLeft,
+                FrontRight,
+                FrontCentre,
+                Lfe,
+                BackLeft,
+                BackRight,
+                SideLeft,
+                SideRight,
+            ],
+            // no standard layout; alternate left and right
+            (channels, _) => (0..channels)
+                .map(|i| if i % 2 == 0 { FrontLeft } else { FrontRight })
+                .collect(),
+        }
+    }
+
+    /// The gain of the speaker in the left and right channels of a stereo
+    /// downmix.
+    fn stereo_gains(self) -> [f32; 2] {
+        match self {
+            Speaker::Mono => [1., 1.],
+            Speaker::FrontLeft => [1., 0.],
+            Speaker::FrontRight => [0., 1.],
+            Speaker::FrontCentre => [FRAC_1_SQRT_2, FRAC_1_SQRT_2],
+            // the sub is usually redundant with the other channels
+            Speaker::Lfe => [0., 0.],
+            Speaker::BackLeft | Speaker::SideLeft => [FRAC_1_SQRT_2, 0.],
+            Speaker::BackRight | Speaker::SideRight => [0., FRAC_1_SQRT_2],
+            Speaker::BackCentre => [0.5, 0.5],
+        }
+    }
+}
+
+/// A source that converts the channel count of another.
+///
+/// Any layout is first mixed down to stereo, using the usual downmix gains
+/// for surround layouts. Stereo is then averaged for mono, or put in the
+/// first two channels of anything wider. When the channel counts already
+/// match, this acts as a passthrough.
+pub struct ChannelConverter<T> {
+    inner: T,
+    to: u8,
+    /// The stereo gains of each input channel.
+    gains: Vec<[f32; 2]>,
     buffer: Vec<i16>,
-    buffer_cursor: usize,
-    stream: OggStreamReader<Cursor<AudioSource>>,
 }
 
-impl OggDecoder {
-    /// Create a new `OggDecoder`.
-    pub fn new(source: AudioSource) -> Result<OggDecoder, lewton::VorbisError> {
-        OggStreamReader::new(Cursor::new(source)).map(|stream| OggDecoder {
-            stream,
+impl<T> ChannelConverter<T>
+where
+    T: Source,
+{
+    /// Creates a new `ChannelConverter`.
+    ///
+    /// `order` is the order of the channels of `inner`, which only matters
+    /// for more than two channels.
+    ///
+    /// # Panics
+    /// Panics if `to` or the `channels` of `inner` is `0`.
+    pub fn new(inner: T, to: u8, order: ChannelOrder) -> ChannelConverter<T> {
+        assert!(to > 0);
+        assert!(inner.channels() > 0);
+
+        let gains = Speaker::layout(inner.channels(), order)
+            .into_iter()
+            .map(Speaker::stereo_gains)
+            .collect();
+
+        ChannelConverter {
+            inner,
+            to,
+            gains,
             buffer: Vec::new(),
-            buffer_cursor: 0,
-        })
+        }
     }
+}
 
-    fn remaining(&self) -> usize {
-        self.buffer.len() - self.buffer_cursor
+impl<T> Source for ChannelConverter<T>
+where
+    T: Source,
+{
+    type Error = T::Error;
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        self.to
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let from = self.inner.channels() as usize;
+        let to = self.to as usize;
+
+        if from == to {
+            return self.inner.sample(buf);
+        }
+
+        let frames = buf.len() / to;
+
+        self.buffer.resize(frames * from, 0);
+        let read_len = self.inner.sample(&mut self.buffer)?;
+        let read_frames = read_len / from;
+
+        let frames_in = self.buffer[..read_len].chunks_exact(from);
+        let frames_out = buf.chunks_exact_mut(to);
+
+        for (frame_in, frame_out) in frames_in.zip(frames_out) {
+            let mut stereo = [0f32; 2];
+
+            for (sample, gains) in frame_in.iter().zip(self.gains.iter()) {
+                let sample = sample.to_sample::<f32>();
+
+                stereo[0] += sample * gains[0];
+                stereo[1] += sample * gains[1];
+            }
+
+            if to == 1 {
+                frame_out[0] = ((stereo[0] + stereo[1]) / 2.).to_sample::<i16>();
+            } else {
+                frame_out[0] = stereo[0].clamp(-1., 1.).to_sample::<i16>();
+                frame_out[1] = stereo[1].clamp(-1., 1.).to_sample::<i16>();
+                frame_out[2..].fill(0);
+            }
+        }
+
+        Ok(read_frames * to)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        self.inner.seek(position)
     }
 }
 
-impl Source for OggDecoder {
-    type Error = lewton::VorbisError;
+/// A source that changes the playback speed of another by resampling it.
+///
+/// Like speeding up a record, this shifts pitch with speed. For a speed
+/// change that keeps pitch, see [`TimeStretch`]. At a rate of `1.0` this acts
+/// as a passthrough.
+pub struct Varispeed<T> {
+    inner: T,
+    rate: f64,
+
+    /// Interleaved frames read from `inner`.
+    input: Vec<i16>,
+    /// Position in `input`, in frames.
+    position: f64,
+    eof: bool,
+}
+
+impl<T> Varispeed<T>
+where
+    T: Source,
+{
+    /// Creates a new `Varispeed` at a rate of `1.0`.
+    pub fn new(inner: T) -> Varispeed<T> {
+        Varispeed {
+            inner,
+            rate: 1.,
+            input: Vec::new(),
+            position: 0.,
+            eof: false,
+        }
+    }
+
+    /// Returns the playback rate.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Sets the playback rate, where `2.0` plays twice as fast.
+    ///
+    /// Rates that are not positive and finite are ignored, since this is
+    /// called from the audio thread.
+    pub fn set_rate(&mut self, rate: f64) {
+        if !(rate > 0. && rate.is_finite()) {
+            return;
+        }
+
+        self.rate = rate;
+    }
+
+    /// Reads more frames from `inner`, dropping frames already played.
+    ///
+    /// Returns `false` if `inner` has run out.
+    fn refill(&mut self, channels: usize) -> Result<bool, T::Error> {
+        // drop played frames
+        let played = self.position as usize;
+        self.input
+            .drain(..(played * channels).min(self.input.len()));
+        self.position -= played as f64;
+
+        let len = self.input.len();
+        self.input.resize(len + STRETCH_READ_FRAMES * channels, 0);
+
+        let read_len = self.inner.sample(&mut self.input[len..])?;
+        self.input.truncate(len + read_len);
+
+        Ok(read_len > 0)
+    }
+}
+
+impl<T> Source for Varispeed<T>
+where
+    T: Source,
+{
+    type Error = T::Error;
 
     fn sample_rate(&self) -> u32 {
-        self.stream.ident_hdr.audio_sample_rate
+        self.inner.sample_rate()
     }
 
     fn channels(&self) -> u8 {
-        self.stream.ident_hdr.audio_channels
+        self.inner.channels()
     }
 
-    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, lewton::VorbisError> {
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        if self.rate == 1. && self.input.is_empty() {
+            return self.inner.sample(buf);
+        }
+
+        let channels = self.inner.channels() as usize;
         let mut cursor = 0;
 
         while cursor < buf.len() {
-            if self.remaining() > 0 {
-                // copy from buffer
-                let remaining = self.remaining();
-                let len = std::cmp::min(remaining, buf.len() - cursor);
+            let index = self.position as usize;
 
-                buf[cursor..(len + cursor)]
-                    .copy_from_slice(&self.buffer[self.buffer_cursor..(self.buffer_cursor + len)]);
+            if (index + 1) * channels >= self.input.len() {
+                // interpolation needs the next frame too
+                if self.eof || !self.refill(channels)? {
+                    self.eof = true;
+                    break;
+                }
 
-                // advance buffer cursor
-                self.buffer_cursor += len;
+                continue;
+            }
 
-                cursor += len;
-            } else {
-                // try to read more data
-                match self.stream.read_dec_packet_itl()? {
-                    Some(data) => {
-                        // reinit buffer
-                        self.buffer = data;
-                        self.buffer_cursor = 0;
-                    }
-                    None => break,
-                }
+            let t = (self.position - index as f64) as f32;
+            let from = &self.input[(index * channels)..((index + 1) * channels)];
+            let to = &self.input[((index + 1) * channels)..((index + 2) * channels)];
+
+            for ((out, from), to) in buf[cursor..(cursor + channels)]
+                .iter_mut()
+                .zip(from)
+                .zip(to)
+            {
+                let from = from.to_sample::<f32>();
+                let to = to.to_sample::<f32>();
+
+                *out = (from + (to - from) * t).to_sample::<i16>();
             }
+
+            cursor += channels;
+            self.position += self.rate;
         }
 
         Ok(cursor)
     }
 
     fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
-        self.stream.seek_absgp_pg(position as u64)?;
+        self.inner.seek(position)?;
 
-        // reset buffers
-        self.buffer.clear();
-        self.buffer_cursor = 0;
+        self.input.clear();
+        self.position = 0.;
+        self.eof = false;
+
+        Ok(())
+    }
+}
+
+/// How many frames [`Varispeed`] and [`TimeStretch`] read from their inner
+/// source at a time.
+const STRETCH_READ_FRAMES: usize = 1024;
+
+/// How long a single grain of [`TimeStretch`] is, in seconds.
+const STRETCH_GRAIN_TIME: f32 = 0.04;
+
+/// Only every nth frame is compared when [`TimeStretch`] aligns grains.
+const STRETCH_CORRELATION_STRIDE: usize = 4;
+
+/// A source that changes the playback speed of another while keeping its
+/// pitch.
+///
+/// This uses WSOLA (waveform similarity overlap-add): the audio is cut into
+/// overlapping grains that are spaced further apart or closer together, and
+/// every grain is nudged to line up with the waveform of the one before it.
+/// Until a rate other than `1.0` is set, this acts as a passthrough, and it
+/// goes back to one once the rate is `1.0` again and the grains have caught
+/// up with the audio.
+pub struct TimeStretch<T> {
+    inner: T,
+    rate: f64,
+    engaged: bool,
+
+    grain_len: usize,
+    tolerance: usize,
+    window: Vec<f32>,
+
+    /// Interleaved frames read from `inner`.
+    input: Vec<f32>,
+    read_buffer: Vec<i16>,
+    /// Position of the next grain in `input`, in frames.
+    position: f64,
+    /// Where the last grain would have naturally continued in `input`.
+    natural: Option<usize>,
+    /// Whether the audio so far ends right at `natural`, so the rest of
+    /// `input` can be played as is.
+    continuous: bool,
+    eof: bool,
+
+    /// Overlap-add accumulator of one grain.
+    overlap: Vec<f32>,
+    output: Vec<i16>,
+    output_cursor: usize,
+}
+
+impl<T> TimeStretch<T>
+where
+    T: Source,
+{
+    /// Creates a new `TimeStretch` at a rate of `1.0`.
+    pub fn new(inner: T) -> TimeStretch<T> {
+        let channels = inner.channels() as usize;
+
+        // grains must be even to split into two hops
+        let grain_len = (inner.sample_rate() as f32 * STRETCH_GRAIN_TIME) as usize & !1;
+        let grain_len = grain_len.max(2);
+
+        // periodic hann window; sums to one at 50% overlap
+        let window = (0..grain_len)
+            .map(|i| {
+                let phase = i as f32 / grain_len as f32;
+                0.5 - 0.5 * (std::f32::consts::TAU * phase).cos()
+            })
+            .collect();
+
+        TimeStretch {
+            inner,
+            rate: 1.,
+            engaged: false,
+            grain_len,
+            tolerance: grain_len / 8,
+            window,
+            input: Vec::new(),
+            read_buffer: vec![0; STRETCH_READ_FRAMES * channels],
+            position: 0.,
+            natural: None,
+            continuous: true,
+            eof: false,
+            overlap: vec![0.; grain_len * channels],
+            output: Vec::new(),
+            output_cursor: 0,
+        }
+    }
+
+    /// Returns the inner source.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns the playback rate.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    /// Sets the playback rate, where `2.0` plays twice as fast.
+    ///
+    /// Rates that are not positive and finite are ignored, since this is
+    /// called from the audio thread.
+    pub fn set_rate(&mut self, rate: f64) {
+        if !(rate > 0. && rate.is_finite()) {
+            return;
+        }
+
+        self.rate = rate;
+
+        if rate != 1. {
+            self.engaged = true;
+        }
+    }
+
+    fn input_frames(&self, channels: usize) -> usize {
+        self.input.len() / channels
+    }
+
+    /// Reads from `inner` until `input` has `frames` frames, or `inner` ends.
+    fn fill_input(&mut self, frames: usize, channels: usize) -> Result<(), T::Error> {
+        while !self.eof && self.input_frames(channels) < frames {
+            let read_len = self.inner.sample(&mut self.read_buffer)?;
+
+            if read_len == 0 {
+                self.eof = true;
+            }
+
+            self.input.extend(
+                self.read_buffer[..read_len]
+                    .iter()
+                    .map(|s| s.to_sample::<f32>()),
+            );
+        }
 
         Ok(())
     }
+
+    /// Finds the grain start around `nominal` that best continues the
+    /// waveform at `natural`.
+    fn align(&self, nominal: usize, natural: usize, channels: usize) -> usize {
+        let hop = self.grain_len / 2;
+        let frames = self.input_frames(channels);
+
+        // mono frame at an index, for comparing
+        let frame = |index: usize| -> f32 {
+            self.input[(index * channels)..((index + 1) * channels)]
+                .iter()
+                .sum()
+        };
+
+        if natural + hop > frames {
+            return nominal;
+        }
+
+        let first = nominal.saturating_sub(self.tolerance);
+        let last = (nominal + self.tolerance).min(frames.saturating_sub(hop));
+
+        let mut best = (nominal, f32::MIN);
+
+        for candidate in first..=last {
+            let mut correlation = 0.;
+            let mut energy = 0.;
+
+            for i in (0..hop).step_by(STRETCH_CORRELATION_STRIDE) {
+                let sample = frame(candidate + i);
+
+                correlation += sample * frame(natural + i);
+                energy += sample * sample;
+            }
+
+            let score = correlation / (energy + f32::EPSILON).sqrt();
+
+            if score > best.1 {
+                best = (candidate, score);
+            }
+        }
+
+        best.0
+    }
+
+    /// Overlap-adds the next grain, emitting one hop of audio to `output`.
+    ///
+    /// Returns `false` if there is no more audio.
+    fn next_hop(&mut self, channels: usize) -> Result<bool, T::Error> {
+        let hop = self.grain_len / 2;
+        let nominal = self.position.round() as usize;
+
+        self.fill_input(nominal + self.tolerance + self.grain_len, channels)?;
+
+        let frames = self.input_frames(channels);
+
+        if self.eof && nominal >= frames && self.overlap.iter().all(|s| *s == 0.) {
+            return Ok(false);
+        }
+
+        let start = match self.natural {
+            // at the normal rate, grains just continue the one before
+            Some(natural) if self.rate == 1. => natural,
+            Some(natural) => self.align(nominal, natural, channels),
+            None => nominal,
+        };
+
+        self.continuous = self.natural == Some(start);
+
+        // overlap-add the grain
+        for i in 0..self.grain_len {
+            let index = start + i;
+
+            if index >= frames {
+                break;
+            }
+
+            let weight = self.window[i];
+            let grain = &self.input[(index * channels)..((index + 1) * channels)];
+            let overlap = &mut self.overlap[(i * channels)..((i + 1) * channels)];
+
+            for (overlap, sample) in overlap.iter_mut().zip(grain) {
+                *overlap += sample * weight;
+            }
+        }
+
+        // the first hop is finished
+        self.output.clear();
+        self.output_cursor = 0;
+        self.output.extend(
+            self.overlap[..(hop * channels)]
+                .iter()
+                .map(|s| s.to_sample::<i16>()),
+        );
+
+        self.overlap.rotate_left(hop * channels);
+        self.overlap[(hop * channels)..].fill(0.);
+
+        self.natural = Some(start + hop);
+        self.position += hop as f64 * self.rate;
+
+        // drop input that no grain can reach anymore
+        let reachable = (self.position as usize).saturating_sub(self.tolerance);
+        let drop = reachable.min(start + hop).min(frames);
+
+        self.input.drain(..(drop * channels));
+        self.position -= drop as f64;
+        self.natural = self.natural.map(|natural| natural - drop);
+
+        Ok(true)
+    }
+
+    /// Plays the rest of `input` as is and goes back to a passthrough.
+    ///
+    /// The audio must be continuous, or this skips.
+    fn disengage(&mut self, channels: usize) {
+        let natural = self.natural.unwrap_or(0);
+
+        self.output.clear();
+        self.output_cursor = 0;
+        self.output.extend(
+            self.input[(natural * channels)..]
+                .iter()
+                .map(|s| s.to_sample::<i16>()),
+        );
+
+        self.input.clear();
+        self.position = 0.;
+        self.natural = None;
+        self.eof = false;
+        self.overlap.fill(0.);
+        self.continuous = true;
+        self.engaged = false;
+    }
+}
+
+impl<T> Source for TimeStretch<T>
+where
+    T: Source,
+{
+    type Error = T::Error;
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        self.inner.channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let channels = self.inner.channels() as usize;
+        let mut cursor = 0;
+
+        while cursor < buf.len() {
+            if self.output_cursor < self.output.len() {
+                let len = min(self.output.len() - self.output_cursor, buf.len() - cursor);
+
+                buf[cursor..(cursor + len)]
+                    .copy_from_slice(&self.output[self.output_cursor..(self.output_cursor + len)]);
+
+                self.output_cursor += len;
+                cursor += len;
+            } else if !self.engaged {
+                return Ok(cursor + self.inner.sample(&mut buf[cursor..])?);
+            } else if self.rate == 1. && self.continuous {
+                self.disengage(channels);
+            } else if !self.next_hop(channels)? {
+                break;
+            }
+        }
+
+        Ok(cursor)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        self.inner.seek(position)?;
+
+        self.input.clear();
+        self.position = 0.;
+        self.natural = None;
+        self.continuous = true;
+        self.eof = false;
+        self.overlap.fill(0.);
+        self.output.clear();
+        self.output_cursor = 0;
+
+        Ok(())
+    }
+}
+
+/// The kind of a [`Biquad`] filter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FilterKind {
+    /// Passes frequencies below the cutoff, which muffles the audio.
+    LowPass,
+    /// Passes frequencies above the cutoff, which thins the audio.
+    HighPass,
+}
+
+/// A source that filters another with a biquad filter.
+///
+/// The coefficients are from the Audio EQ Cookbook. Until a cutoff is set,
+/// this acts as a passthrough.
+pub struct Biquad<T> {
+    inner: T,
+    kind: FilterKind,
+    cutoff: Option<f32>,
+    q: f32,
+
+    /// Normalized `b0`, `b1`, `b2`, `a1` and `a2`.
+    coefficients: [f32; 5],
+    /// The last two inputs and outputs of every channel.
+    history: Vec<[f32; 4]>,
+}
+
+impl<T> Biquad<T>
+where
+    T: Source,
+{
+    /// Creates a new, disabled `Biquad`.
+    pub fn new(inner: T, kind: FilterKind) -> Biquad<T> {
+        let channels = inner.channels() as usize;
+
+        Biquad {
+            inner,
+            kind,
+            cutoff: None,
+            q: FRAC_1_SQRT_2,
+            coefficients: [1., 0., 0., 0., 0.],
+            history: vec![[0.; 4]; channels],
+        }
+    }
+
+    /// Returns the cutoff frequency in Hz.
+    pub fn cutoff(&self) -> Option<f32> {
+        self.cutoff
+    }
+
+    /// Sets the cutoff frequency in Hz, or disables the filter with `None`.
+    pub fn set_cutoff(&mut self, cutoff: Option<f32>) {
+        if cutoff != self.cutoff {
+            self.cutoff = cutoff;
+            self.update_coefficients();
+        }
+    }
+
+    /// Sets the resonance of the filter.
+    ///
+    /// The default of `1/sqrt(2)` has the flattest response.
+    pub fn set_q(&mut self, q: f32) {
+        if q != self.q {
+            self.q = q.max(0.01);
+            self.update_coefficients();
+        }
+    }
+
+    /// Returns a mutable reference to the inner source.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    fn update_coefficients(&mut self) {
+        let Some(cutoff) = self.cutoff else {
+            return;
+        };
+
+        let sample_rate = self.inner.sample_rate() as f32;
+        let cutoff = cutoff.clamp(10., sample_rate * 0.49);
+
+        let w0 = std::f32::consts::TAU * cutoff / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * self.q);
+
+        let (b0, b1, b2) = match self.kind {
+            FilterKind::LowPass => ((1. - cos) / 2., 1. - cos, (1. - cos) / 2.),
+            FilterKind::HighPass => ((1. + cos) / 2., -(1. + cos), (1. + cos) / 2.),
+        };
+        let (a0, a1, a2) = (1. + alpha, -2. * cos, 1. - alpha);
+
+        self.coefficients = [b0 / a0, b1 / a0, b2 / a0, a1 / a0, a2 / a0];
+    }
+}
+
+impl<T> Source for Biquad<T>
+where
+    T: Source,
+{
+    type Error = T::Error;
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        self.inner.channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let len = self.inner.sample(buf)?;
+
+        if self.cutoff.is_none() {
+            return Ok(len);
+        }
+
+        let [b0, b1, b2, a1, a2] = self.coefficients;
+
+        for frame in buf[..len].chunks_mut(self.history.len()) {
+            for (sample, history) in frame.iter_mut().zip(self.history.iter_mut()) {
+                let [x1, x2, y1, y2] = *history;
+                let x = sample.to_sample::<f32>();
+                let y = b0 * x + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+
+                *history = [x, x1, y, y1];
+                *sample = y.to_sample::<i16>();
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        self.inner.seek(position)?;
+
+        self.history.fill([0.; 4]);
+
+        Ok(())
+    }
+}
+
+/// The shape of a [`Fade`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum FadeCurve {
+    /// The gain changes at a constant rate.
+    #[default]
+    Linear,
+    /// The gain follows a quarter sine, so two sources fading in and out
+    /// over each other keep a constant loudness.
+    EqualPower,
+}
+
+/// A source that fades another in or out.
+///
+/// At a gain of `1.0` with no fade in progress, this acts as a passthrough.
+pub struct Fade<T> {
+    inner: T,
+    gain: f32,
+
+    curve: FadeCurve,
+    from: f32,
+    to: f32,
+    /// Progress of the fade, in frames.
+    progress: usize,
+    /// Length of the fade, in frames.
+    length: usize,
+}
+
+impl<T> Fade<T>
+where
+    T: Source,
+{
+    /// Creates a new `Fade` at a gain of `1.0`.
+    pub fn new(inner: T) -> Fade<T> {
+        Fade {
+            inner,
+            gain: 1.,
+            curve: FadeCurve::Linear,
+            from: 1.,
+            to: 1.,
+            progress: 0,
+            length: 0,
+        }
+    }
+
+    /// Returns the current gain.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Sets the gain right away, cancelling any fade in progress.
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain.max(0.);
+        self.progress = 0;
+        self.length = 0;
+    }
+
+    /// Checks if a fade is in progress.
+    pub fn is_fading(&self) -> bool {
+        self.progress < self.length
+    }
+
+    /// Fades from the current gain to `to` over `duration`.
+    pub fn fade_to(&mut self, to: f32, duration: Duration, curve: FadeCurve) {
+        let length = duration.as_secs_f64() * self.inner.sample_rate() as f64;
+
+        self.curve = curve;
+        self.from = self.gain;
+        self.to = to.max(0.);
+        self.progress = 0;
+        self.length = length as usize;
+
+        if self.length == 0 {
+            self.gain = self.to;
+        }
+    }
+
+    /// Returns a mutable reference to the inner source.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// The gain at a point `t` through the fade, from `0` to `1`.
+    fn gain_at(&self, t: f32) -> f32 {
+        if t >= 1. {
+            // land exactly on the target, so faded out audio is silent
+            return self.to;
+        }
+
+        match self.curve {
+            FadeCurve::Linear => self.from + (self.to - self.from) * t,
+            FadeCurve::EqualPower if self.to > self.from => {
+                self.from + (self.to - self.from) * (t * FRAC_PI_2).sin()
+            }
+            FadeCurve::EqualPower => self.to + (self.from - self.to) * (t * FRAC_PI_2).cos(),
+        }
+    }
+}
+
+impl<T> Source for Fade<T>
+where
+    T: Source,
+{
+    type Error = T::Error;
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        self.inner.channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let len = self.inner.sample(buf)?;
+
+        if self.gain == 1. && !self.is_fading() {
+            return Ok(len);
+        }
+
+        let channels = self.inner.channels() as usize;
+
+        for frame in buf[..len].chunks_mut(channels) {
+            if self.is_fading() {
+                self.progress += 1;
+                self.gain = self.gain_at(self.progress as f32 / self.length as f32);
+            }
+
+            for sample in frame.iter_mut() {
+                *sample = (sample.to_sample::<f32>() * self.gain).to_sample::<i16>();
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        self.inner.seek(position)
+    }
+}
+
+/// Comb filter delays of [`Reverb`] at 44.1kHz, in frames.
+const REVERB_COMB_DELAYS: [usize; 4] = [1116, 1188, 1277, 1356];
+
+/// Allpass filter delays of [`Reverb`] at 44.1kHz, in frames.
+const REVERB_ALLPASS_DELAYS: [usize; 2] = [556, 441];
+
+/// How much further the delays of each channel are spread, in frames.
+const REVERB_STEREO_SPREAD: usize = 23;
+
+/// How long the reverb rings; closer to `1` is a bigger room.
+const REVERB_FEEDBACK: f32 = 0.84;
+
+/// How much the reverb softens high frequencies as it rings.
+const REVERB_DAMPING: f32 = 0.2;
+
+/// Scales the input of the reverb so the combs don't clip.
+const REVERB_INPUT_GAIN: f32 = 0.015;
+
+/// A source that adds reverb to another.
+///
+/// This is a small Schroeder reverb in the style of Freeverb: parallel comb
+/// filters into allpass filters, per channel. With a mix of `0.0`, this acts
+/// as a passthrough.
+pub struct Reverb<T> {
+    inner: T,
+    mix: f32,
+    channels: Vec<ReverbChannel>,
+}
+
+struct ReverbChannel {
+    combs: Vec<(Vec<f32>, usize, f32)>,
+    allpasses: Vec<(Vec<f32>, usize)>,
+}
+
+impl<T> Reverb<T>
+where
+    T: Source,
+{
+    /// Creates a new `Reverb` with a mix of `0.0`.
+    pub fn new(inner: T) -> Reverb<T> {
+        let scale = inner.sample_rate() as f32 / 44_100.;
+        let delay = |frames: usize, channel: usize| {
+            let frames = frames + channel * REVERB_STEREO_SPREAD;
+            vec![0.; ((frames as f32 * scale) as usize).max(1)]
+        };
+
+        let channels = (0..inner.channels() as usize)
+            .map(|channel| ReverbChannel {
+                combs: REVERB_COMB_DELAYS
+                    .iter()
+                    .map(|frames| (delay(*frames, channel), 0, 0.))
+                    .collect(),
+                allpasses: REVERB_ALLPASS_DELAYS
+                    .iter()
+                    .map(|frames| (delay(*frames, channel), 0))
+                    .collect(),
+            })
+            .collect();
+
+        Reverb {
+            inner,
+            mix: 0.,
+            channels,
+        }
+    }
+
+    /// Returns how much of the output is reverb, from `0` to `1`.
+    pub fn mix(&self) -> f32 {
+        self.mix
+    }
+
+    /// Sets how much of the output is reverb, from `0` to `1`.
+    pub fn set_mix(&mut self, mix: f32) {
+        let mix = mix.clamp(0., 1.);
+
+        if mix == 0. && self.mix != 0. {
+            // don't ring out old audio when turned back on
+            self.clear();
+        }
+
+        self.mix = mix;
+    }
+
+    /// Returns a mutable reference to the inner source.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    fn clear(&mut self) {
+        for channel in self.channels.iter_mut() {
+            for (buffer, _, filter) in channel.combs.iter_mut() {
+                buffer.fill(0.);
+                *filter = 0.;
+            }
+
+            for (buffer, _) in channel.allpasses.iter_mut() {
+                buffer.fill(0.);
+            }
+        }
+    }
+}
+
+impl ReverbChannel {
+    fn process(&mut self, input: f32) -> f32 {
+        let mut output = 0.;
+
+        for (buffer, cursor, filter) in self.combs.iter_mut() {
+            let delayed = buffer[*cursor];
+
+            *filter = delayed * (1. - REVERB_DAMPING) + *filter * REVERB_DAMPING;
+            buffer[*cursor] = input + *filter * REVERB_FEEDBACK;
+            *cursor = (*cursor + 1) % buffer.len();
+
+            output += delayed;
+        }
+
+        for (buffer, cursor) in self.allpasses.iter_mut() {
+            let delayed = buffer[*cursor];
+
+            buffer[*cursor] = output + delayed * 0.5;
+            *cursor = (*cursor + 1) % buffer.len();
+
+            output = delayed - output;
+        }
+
+        output
+    }
+}
+
+impl<T> Source for Reverb<T>
+where
+    T: Source,
+{
+    type Error = T::Error;
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        self.inner.channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let len = self.inner.sample(buf)?;
+
+        if self.mix == 0. {
+            return Ok(len);
+        }
+
+        for frame in buf[..len].chunks_mut(self.channels.len()) {
+            for (sample, channel) in frame.iter_mut().zip(self.channels.iter_mut()) {
+                let dry = sample.to_sample::<f32>();
+                let wet = channel.process(dry * REVERB_INPUT_GAIN);
+
+                *sample = (dry * (1. - self.mix) + wet * self.mix).to_sample::<i16>();
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        self.inner.seek(position)?;
+
+        self.clear();
+
+        Ok(())
+    }
+}
+
+/// The level a [`Ducking`] key has to pass to duck.
+const DUCKING_THRESHOLD: f32 = 0.01;
+
+/// How fast a [`Ducking`] source ducks, in seconds.
+const DUCKING_ATTACK_TIME: f32 = 0.01;
+
+/// How fast a [`Ducking`] source recovers, in seconds.
+const DUCKING_RELEASE_TIME: f32 = 0.3;
+
+/// A source that lowers the gain of another while a key signal is loud.
+///
+/// This is sidechain ducking, like music quieting under dialogue. The level
+/// of the key is fed in with [`Ducking::set_key_level`] before sampling.
+/// While the key is silent and the gain has recovered, this acts as a
+/// passthrough.
+pub struct Ducking<T> {
+    inner: T,
+    depth: f32,
+    key_level: f32,
+    gain: f32,
+
+    attack: f32,
+    release: f32,
+}
+
+impl<T> Ducking<T>
+where
+    T: Source,
+{
+    /// Creates a new `Ducking` with a depth of `1.0`, which never ducks.
+    pub fn new(inner: T) -> Ducking<T> {
+        let sample_rate = inner.sample_rate() as f32;
+
+        Ducking {
+            inner,
+            depth: 1.,
+            key_level: 0.,
+            gain: 1.,
+            attack: 1. - (-1. / (DUCKING_ATTACK_TIME * sample_rate)).exp(),
+            release: 1. - (-1. / (DUCKING_RELEASE_TIME * sample_rate)).exp(),
+        }
+    }
+
+    /// Sets the gain of the source while ducked.
+    pub fn set_depth(&mut self, depth: f32) {
+        self.depth = depth.clamp(0., 1.);
+    }
+
+    /// Sets the current level of the key signal.
+    pub fn set_key_level(&mut self, level: f32) {
+        self.key_level = level;
+    }
+
+    /// Returns a mutable reference to the inner source.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T> Source for Ducking<T>
+where
+    T: Source,
+{
+    type Error = T::Error;
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        self.inner.channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let len = self.inner.sample(buf)?;
+
+        let target = if self.key_level > DUCKING_THRESHOLD {
+            self.depth
+        } else {
+            1.
+        };
+
+        if target == 1. && self.gain == 1. {
+            return Ok(len);
+        }
+
+        let smoothing = if target < self.gain {
+            self.attack
+        } else {
+            self.release
+        };
+        let channels = self.inner.channels() as usize;
+
+        for frame in buf[..len].chunks_mut(channels) {
+            self.gain += (target - self.gain) * smoothing;
+
+            if (target - self.gain).abs() < 1e-4 {
+                self.gain = target;
+            }
+
+            for sample in frame.iter_mut() {
+                *sample = (sample.to_sample::<f32>() * self.gain).to_sample::<i16>();
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        self.inner.seek(position)
+    }
+}
+
+/// A source that loops a region of another.
+///
+/// Positions stay monotonic: they count every frame played, and seeking past
+/// the end of the loop lands in the loop as if it had played through. Loop
+/// points are at the sample rate of the inner source, so this goes right
+/// after the decoder; a [`Resampler`] after it sees one continuous stream,
+/// and the loop doesn't click. Without a loop region, this acts as a
+/// passthrough.
+///
+/// Every loop seeks the inner source, so it should seek cheaply. A FLAC file
+/// without a SEEKTABLE is decoded from the start on every loop.
+pub struct Looped<T> {
+    inner: T,
+    region: Option<LoopRegion>,
+    /// The position in the inner source, in frames.
+    position: usize,
+    /// The length of the inner source in frames, once it is known.
+    length: Option<usize>,
+    loops: Arc<AtomicU64>,
+}
+
+impl<T> Looped<T>
+where
+    T: Source,
+{
+    /// Creates a new `Looped`, counting the times it loops in `loops`.
+    pub fn new(inner: T, region: Option<LoopRegion>, loops: Arc<AtomicU64>) -> Looped<T> {
+        Looped {
+            inner,
+            region,
+            position: 0,
+            length: None,
+            loops,
+        }
+    }
+
+    /// Returns how many times the source has looped.
+    pub fn loop_count(&self) -> u64 {
+        self.loops.load(Ordering::Acquire)
+    }
+
+    /// Returns a mutable reference to the inner source.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// The frame the loop ends on, if known.
+    fn end(&self) -> Option<usize> {
+        let region = self.region?;
+
+        region.end.map(|end| end as usize).or(self.length)
+    }
+}
+
+impl<T> Source for Looped<T>
+where
+    T: Source,
+{
+    type Error = T::Error;
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn channels(&self) -> u8 {
+        self.inner.channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let Some(region) = self.region else {
+            return self.inner.sample(buf);
+        };
+
+        let channels = self.inner.channels() as usize;
+        let mut filled = 0;
+        // an empty loop would wrap forever
+        let mut played = true;
+
+        while filled < buf.len() {
+            let mut len = buf.len() - filled;
+
+            if let Some(end) = self.end() {
+                len = min(len, end.saturating_sub(self.position) * channels);
+            }
+
+            let read = if len > 0 {
+                self.inner.sample(&mut buf[filled..(filled + len)])?
+            } else {
+                0
+            };
+
+            filled += read;
+            self.position += read / channels;
+
+            if read > 0 {
+                played = true;
+                continue;
+            }
+
+            if len > 0 && self.length.is_none() {
+                // ran out before the loop end
+                self.length = Some(self.position);
+            }
+
+            if !played {
+                break;
+            }
+
+            self.inner.seek(region.start as usize)?;
+            self.position = region.start as usize;
+            self.loops.fetch_add(1, Ordering::AcqRel);
+
+            played = false;
+        }
+
+        Ok(filled)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        let start = self.region.map_or(0, |r| r.start as usize);
+
+        let (position, loops) = match self.end() {
+            Some(end) if end > start && position >= end => {
+                let over = position - start;
+                let len = end - start;
+
+                (start + over % len, over / len)
+            }
+            _ => (position, 0),
+        };
+
+        self.inner.seek(position)?;
+        self.position = position;
+        self.loops.store(loops as u64, Ordering::Release);
+
+        Ok(())
+    }
+}
+
+/// An `.ogg` decoder for an audio asset.
+pub struct OggDecoder {
+    buffer: Vec<i16>,
+    buffer_cursor: usize,
+    stream: OggStreamReader<Cursor<AudioSource>>,
+}
+
+impl OggDecoder {
+    /// Create a new `OggDecoder`.
+    pub fn new(source: AudioSource) -> Result<OggDecoder, lewton::VorbisError> {
+        OggStreamReader::new(Cursor::new(source)).map(|stream| OggDecoder {
+            stream,
+            buffer: Vec::new(),
+            buffer_cursor: 0,
+        })
+    }
+
+    fn remaining(&self) -> usize {
+        self.buffer.len() - self.buffer_cursor
+    }
+}
+
+impl Source for OggDecoder {
+    type Error = lewton::VorbisError;
+
+    fn sample_rate(&self) -> u32 {
+        self.stream.ident_hdr.audio_sample_rate
+    }
+
+    fn channels(&self) -> u8 {
+        self.stream.ident_hdr.audio_channels
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, lewton::VorbisError> {
+        let mut cursor = 0;
+
+        while cursor < buf.len() {
+            if self.remaining() > 0 {
+                // copy from buffer
+                let remaining = self.remaining();
+                let len = std::cmp::min(remaining, buf.len() - cursor);
+
+                buf[cursor..(len + cursor)]
+                    .copy_from_slice(&self.buffer[self.buffer_cursor..(self.buffer_cursor + len)]);
+
+                // advance buffer cursor
+                self.buffer_cursor += len;
+
+                cursor += len;
+            } else {
+                // try to read more data
+                match self.stream.read_dec_packet_itl()? {
+                    Some(data) => {
+                        // reinit buffer
+                        self.buffer = data;
+                        self.buffer_cursor = 0;
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        Ok(cursor)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        // this only seeks to the start of the page `position` is in
+        self.stream.seek_absgp_pg(position as u64)?;
+
+        // reset buffers
+        self.buffer.clear();
+        self.buffer_cursor = 0;
+
+        // decode until the end of a page, where the absolute position is known
+        let end = loop {
+            match self.stream.read_dec_packet_itl()? {
+                Some(data) => {
+                    self.buffer.extend(data);
+
+                    if let Some(absgp) = self.stream.get_last_absgp() {
+                        break absgp as usize;
+                    }
+                }
+                None => {
+                    // seeked past the end of the stream
+                    self.buffer.clear();
+                    return Ok(());
+                }
+            }
+        };
+
+        // skip what was decoded before `position`
+        let channels = self.channels() as usize;
+        let start = end.saturating_sub(self.buffer.len() / channels);
+        let skip = position.saturating_sub(start) * channels;
+
+        self.buffer_cursor = min(skip, self.buffer.len());
+
+        Ok(())
+    }
+}
+
+/// A decoder for any supported [`AudioSource`].
+///
+/// This picks the decoder based on [`AudioSource::format`].
+#[allow(clippy::large_enum_variant)] // decoders are long-lived, not moved around
+pub enum Decoder {
+    Ogg(OggDecoder),
+    Wav(WavDecoder),
+    Flac(FlacDecoder),
+}
+
+impl Decoder {
+    /// Creates a new `Decoder`.
+    pub fn new(source: AudioSource) -> Result<Decoder, DecodeError> {
+        match source.format {
+            AudioFormat::Ogg => Ok(Decoder::Ogg(OggDecoder::new(source)?)),
+            AudioFormat::Wav => Ok(Decoder::Wav(WavDecoder::new(source)?)),
+            AudioFormat::Flac => Ok(Decoder::Flac(FlacDecoder::new(source)?)),
+        }
+    }
+
+    /// The order of the channels the decoder outputs.
+    pub fn channel_order(&self) -> ChannelOrder {
+        match self {
+            Decoder::Ogg(_) => ChannelOrder::Vorbis,
+            Decoder::Wav(_) | Decoder::Flac(_) => ChannelOrder::Smpte,
+        }
+    }
+}
+
+impl Source for Decoder {
+    type Error = DecodeError;
+
+    fn sample_rate(&self) -> u32 {
+        match self {
+            Decoder::Ogg(decoder) => decoder.sample_rate(),
+            Decoder::Wav(decoder) => decoder.sample_rate(),
+            Decoder::Flac(decoder) => decoder.sample_rate(),
+        }
+    }
+
+    fn channels(&self) -> u8 {
+        match self {
+            Decoder::Ogg(decoder) => decoder.channels(),
+            Decoder::Wav(decoder) => decoder.channels(),
+            Decoder::Flac(decoder) => decoder.channels(),
+        }
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        match self {
+            Decoder::Ogg(decoder) => decoder.sample(buf).map_err(Into::into),
+            Decoder::Wav(decoder) => decoder.sample(buf).map_err(Into::into),
+            Decoder::Flac(decoder) => decoder.sample(buf).map_err(Into::into),
+        }
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        match self {
+            Decoder::Ogg(decoder) => decoder.seek(position).map_err(Into::into),
+            Decoder::Wav(decoder) => decoder.seek(position).map_err(Into::into),
+            Decoder::Flac(decoder) => decoder.seek(position).map_err(Into::into),
+        }
+    }
+}
+
+/// An error that [`Decoder`] returns.
+#[derive(Debug)]
+pub enum DecodeError {
+    Vorbis(lewton::VorbisError),
+    Wav(WavError),
+    Flac(claxon::Error),
+}
+
+impl From<lewton::VorbisError> for DecodeError {
+    fn from(value: lewton::VorbisError) -> Self {
+        DecodeError::Vorbis(value)
+    }
+}
+
+impl From<WavError> for DecodeError {
+    fn from(value: WavError) -> Self {
+        DecodeError::Wav(value)
+    }
+}
+
+impl From<claxon::Error> for DecodeError {
+    fn from(value: claxon::Error) -> Self {
+        DecodeError::Flac(value)
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Vorbis(err) => Display::fmt(err, f),
+            DecodeError::Wav(err) => Display::fmt(err, f),
+            DecodeError::Flac(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Vorbis(err) => Some(err),
+            DecodeError::Wav(err) => Some(err),
+            DecodeError::Flac(err) => Some(err),
+        }
+    }
+}
+
+/// A `.wav` decoder for an audio asset.
+///
+/// Supports uncompressed integer PCM of 8, 16, 24 and 32 bits, and 32 or 64
+/// bit float PCM.
+pub struct WavDecoder {
+    source: AudioSource,
+    format: WavFormat,
+    /// The range of the `data` chunk in `source`.
+    data: Range<usize>,
+    /// The read position in the `data` chunk, in bytes.
+    cursor: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct WavFormat {
+    float: bool,
+    channels: u8,
+    sample_rate: u32,
+    block_align: usize,
+    bits_per_sample: u16,
+}
+
+/// `WAVE_FORMAT_PCM`
+const WAV_FORMAT_PCM: u16 = 0x0001;
+/// `WAVE_FORMAT_IEEE_FLOAT`
+const WAV_FORMAT_FLOAT: u16 = 0x0003;
+/// `WAVE_FORMAT_EXTENSIBLE`
+const WAV_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+impl WavDecoder {
+    /// Create a new `WavDecoder`.
+    pub fn new(source: AudioSource) -> Result<WavDecoder, WavError> {
+        let bytes = source.as_ref();
+
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(WavError::Malformed("not a RIFF WAVE file"));
+        }
+
+        let mut format = None;
+        let mut data = None;
+        let mut cursor = 12;
+
+        // walk chunks
+        while cursor + 8 <= bytes.len() {
+            let id = &bytes[cursor..(cursor + 4)];
+            let len = read_u32(bytes, cursor + 4) as usize;
+            let start = cursor + 8;
+            // some writers leave the length unset when streaming
+            let end = start.saturating_add(len).min(bytes.len());
+
+            match id {
+                b"fmt " => format = Some(WavFormat::parse(&bytes[start..end])?),
+                b"data" => data = Some(start..end),
+                _ => (),
+            }
+
+            // chunks are padded to an even length
+            cursor = end + (len & 1);
+        }
+
+        let format = format.ok_or(WavError::Malformed("missing fmt chunk"))?;
+        let data = data.ok_or(WavError::Malformed("missing data chunk"))?;
+
+        Ok(WavDecoder {
+            source,
+            format,
+            data,
+            cursor: 0,
+        })
+    }
+
+    /// The length of the audio, in frames.
+    pub fn frames(&self) -> u64 {
+        (self.data.len() / self.format.block_align) as u64
+    }
+
+    fn read_sample(&self, offset: usize) -> i16 {
+        let bytes = &self.source.as_ref()[offset..];
+
+        match (self.format.float, self.format.bits_per_sample) {
+            (false, 8) => (bytes[0] as i16 - 128) << 8,
+            (false, 16) => i16::from_le_bytes([bytes[0], bytes[1]]),
+            // take the most significant bits
+            (false, 24) => i16::from_le_bytes([bytes[1], bytes[2]]),
+            (false, _) => i16::from_le_bytes([bytes[2], bytes[3]]),
+            (true, 32) => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+                .clamp(-1., 1.)
+                .to_sample::<i16>(),
+            (true, _) => {
+                let mut buf = [0; 8];
+                buf.copy_from_slice(&bytes[..8]);
+
+                f64::from_le_bytes(buf).clamp(-1., 1.).to_sample::<i16>()
+            }
+        }
+    }
+}
+
+impl WavFormat {
+    fn parse(chunk: &[u8]) -> Result<WavFormat, WavError> {
+        if chunk.len() < 16 {
+            return Err(WavError::Malformed("fmt chunk too short"));
+        }
+
+        let mut format_tag = read_u16(chunk, 0);
+        let channels = read_u16(chunk, 2);
+        let sample_rate = read_u32(chunk, 4);
+        let block_align = read_u16(chunk, 12) as usize;
+        let bits_per_sample = read_u16(chunk, 14);
+
+        if format_tag == WAV_FORMAT_EXTENSIBLE {
+            // the real format is at the start of the subformat guid
+            if chunk.len() < 26 {
+                return Err(WavError::Malformed("fmt chunk too short"));
+            }
+
+            format_tag = read_u16(chunk, 24);
+        }
+
+        let float = match (format_tag, bits_per_sample) {
+            (WAV_FORMAT_PCM, 8 | 16 | 24 | 32) => false,
+            (WAV_FORMAT_FLOAT, 32 | 64) => true,
+            (WAV_FORMAT_PCM | WAV_FORMAT_FLOAT, _) => {
+                return Err(WavError::Unsupported("sample bit depth"))
+            }
+            _ => return Err(WavError::Unsupported("compressed format")),
+        };
+
+        let channels = u8::try_from(channels)
+            .ok()
+            .filter(|c| *c > 0)
+            .ok_or(WavError::Unsupported("channel count"))?;
+
+        if sample_rate == 0 {
+            return Err(WavError::Malformed("sample rate of zero"));
+        }
+
+        if block_align < channels as usize * (bits_per_sample as usize / 8) {
+            return Err(WavError::Malformed("block align too small"));
+        }
+
+        Ok(WavFormat {
+            float,
+            channels,
+            sample_rate,
+            block_align,
+            bits_per_sample,
+        })
+    }
+}
+
+impl Source for WavDecoder {
+    type Error = WavError;
+
+    fn sample_rate(&self) -> u32 {
+        self.format.sample_rate
+    }
+
+    fn channels(&self) -> u8 {
+        self.format.channels
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, WavError> {
+        let channels = self.format.channels as usize;
+        let block_align = self.format.block_align;
+        let sample_len = self.format.bits_per_sample as usize / 8;
+
+        let remaining = (self.data.len() - self.cursor) / block_align;
+        let frames = min(buf.len() / channels, remaining);
+
+        for (i, frame) in buf.chunks_exact_mut(channels).take(frames).enumerate() {
+            let offset = self.data.start + self.cursor + i * block_align;
+
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = self.read_sample(offset + channel * sample_len);
+            }
+        }
+
+        self.cursor += frames * block_align;
+
+        Ok(frames * channels)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        let frames = self.data.len() / self.format.block_align;
+
+        self.cursor = min(position, frames) * self.format.block_align;
+
+        Ok(())
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// An error that [`WavDecoder`] returns.
+#[derive(Debug)]
+pub enum WavError {
+    /// The file is not a valid `.wav`.
+    Malformed(&'static str),
+    /// The file is valid, but uses a feature that isn't supported.
+    Unsupported(&'static str),
+}
+
+impl Display for WavError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WavError::Malformed(what) => write!(f, "malformed wav: {}", what),
+            WavError::Unsupported(what) => write!(f, "unsupported wav: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for WavError {}
+
+/// How many frames are sampled at once when rendering.
+const RENDER_BUFFER_FRAMES: usize = 4096;
+
+/// Renders `source` to a 16-bit `.wav`, as fast as it can be sampled.
+///
+/// Rendering stops when the source ends, or after `max_frames` frames if
+/// given; a [`Mixer`](super::Mixer) never ends, so it needs a limit. This
+/// doesn't need an audio device, which makes it suited to regression tests
+/// and exporting replays. Returns how many frames were written.
+pub fn render_wav<S, W>(
+    source: &mut S,
+    max_frames: Option<u64>,
+    mut writer: W,
+) -> Result<u64, RenderError<S::Error>>
+where
+    S: Source + ?Sized,
+    W: Write + Seek,
+{
+    let channels = source.channels() as usize;
+    let sample_rate = source.sample_rate();
+
+    let start = writer.stream_position()?;
+
+    // the lengths are filled in once they are known
+    write_wav_header(&mut writer, channels as u16, sample_rate, 0)?;
+
+    let mut buf = vec![0; RENDER_BUFFER_FRAMES * channels];
+    let mut bytes = Vec::with_capacity(buf.len() * 2);
+    let mut frames = 0u64;
+
+    loop {
+        let frames_left = max_frames.map_or(usize::MAX, |max| (max - frames) as usize);
+        let len = min(buf.len(), frames_left.saturating_mul(channels));
+
+        let len = if len > 0 {
+            source
+                .sample(&mut buf[..len])
+                .map_err(RenderError::Source)?
+        } else {
+            0
+        };
+
+        if len == 0 {
+            break;
+        }
+
+        bytes.clear();
+        bytes.extend(buf[..len].iter().flat_map(|s| s.to_le_bytes()));
+
+        let data_len = (frames + (len / channels) as u64) * channels as u64 * 2;
+
+        if data_len > (u32::MAX - 36) as u64 {
+            return Err(RenderError::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too long for a wav file",
+            )));
+        }
+
+        writer.write_all(&bytes)?;
+        frames += (len / channels) as u64;
+    }
+
+    let end = writer.stream_position()?;
+    let data_len = frames * channels as u64 * 2;
+
+    writer.seek(SeekFrom::Start(start))?;
+    write_wav_header(&mut writer, channels as u16, sample_rate, data_len as u32)?;
+    writer.seek(SeekFrom::Start(end))?;
+    writer.flush()?;
+
+    Ok(frames)
+}
+
+/// Renders `source` to a 16-bit `.wav` file at `path`.
+///
+/// See [`render_wav`].
+pub fn render_wav_file<S>(
+    source: &mut S,
+    max_frames: Option<u64>,
+    path: impl AsRef<Path>,
+) -> Result<u64, RenderError<S::Error>>
+where
+    S: Source + ?Sized,
+{
+    let file = File::create(path)?;
+
+    render_wav(source, max_frames, BufWriter::new(file))
+}
+
+/// Writes the header of a 16-bit PCM `.wav` with `data_len` bytes of
+/// samples.
+fn write_wav_header(
+    writer: &mut impl Write,
+    channels: u16,
+    sample_rate: u32,
+    data_len: u32,
+) -> io::Result<()> {
+    let block_align = channels * 2;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&WAV_FORMAT_PCM.to_le_bytes())?;
+    writer.write_all(&channels.to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&16u16.to_le_bytes())?;
+
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())
+}
+
+/// An error that [`render_wav`] returns.
+#[derive(Debug)]
+pub enum RenderError<T> {
+    Source(T),
+    Io(io::Error),
+}
+
+impl<T> From<io::Error> for RenderError<T> {
+    fn from(value: io::Error) -> RenderError<T> {
+        RenderError::Io(value)
+    }
+}
+
+impl<T> Display for RenderError<T>
+where
+    T: Display,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::Source(err) => Display::fmt(err, f),
+            RenderError::Io(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl<T> std::error::Error for RenderError<T>
+where
+    T: std::error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RenderError::Source(err) => Some(err),
+            RenderError::Io(err) => Some(err),
+        }
+    }
+}
+
+/// A `.flac` decoder for an audio asset.
+pub struct FlacDecoder {
+    source: AudioSource,
+    stream: FrameReader<Cursor<AudioSource>>,
+    streaminfo: StreamInfo,
+    /// The byte offset of the first frame.
+    frames_start: u64,
+    /// The `(frame, byte offset)` points of the SEEKTABLE, sorted by frame.
+    seek_points: Vec<(u64, u64)>,
+
+    /// Interleaved samples of the last decoded block.
+    buffer: Vec<i16>,
+    buffer_cursor: usize,
+    block_buffer: Vec<i32>,
+}
+
+impl FlacDecoder {
+    /// Create a new `FlacDecoder`.
+    pub fn new(source: AudioSource) -> Result<FlacDecoder, claxon::Error> {
+        let streaminfo = FlacReader::new(Cursor::new(source.clone()))?.streaminfo();
+
+        if streaminfo.channels > u8::MAX as u32 {
+            return Err(claxon::Error::Unsupported("channel count"));
+        }
+
+        let (frames_start, seek_points) = read_flac_seek_table(source.as_ref())
+            .ok_or(claxon::Error::FormatError("invalid metadata blocks"))?;
+
+        let mut cursor = Cursor::new(source.clone());
+        cursor.set_position(frames_start);
+
+        Ok(FlacDecoder {
+            source,
+            stream: FrameReader::new(cursor),
+            streaminfo,
+            frames_start,
+            seek_points,
+            buffer: Vec::new(),
+            buffer_cursor: 0,
+            block_buffer: Vec::new(),
+        })
+    }
+
+    fn remaining(&self) -> usize {
+        self.buffer.len() - self.buffer_cursor
+    }
+
+    /// Decodes the next block into `buffer`.
+    ///
+    /// Returns the position of the first frame of the block, or `None` if
+    /// the stream has ended.
+    fn next_block(&mut self) -> Result<Option<u64>, claxon::Error> {
+        let block_buffer = std::mem::take(&mut self.block_buffer);
+
+        let Some(block) = self.stream.read_next_or_eof(block_buffer)? else {
+            return Ok(None);
+        };
+
+        let time = block.time();
+        // scale everything to 16 bits
+        let bits = self.streaminfo.bits_per_sample;
+
+        self.buffer.clear();
+        self.buffer_cursor = 0;
+
+        for frame in 0..block.duration() {
+            for channel in 0..block.channels() {
+                let sample = block.sample(channel, frame);
+
+                let sample = if bits > 16 {
+                    sample >> (bits - 16)
+                } else {
+                    sample << (16 - bits)
+                };
+
+                self.buffer.push(sample as i16);
+            }
+        }
+
+        self.block_buffer = block.into_buffer();
+
+        Ok(Some(time))
+    }
+}
+
+impl Source for FlacDecoder {
+    type Error = claxon::Error;
+
+    fn sample_rate(&self) -> u32 {
+        self.streaminfo.sample_rate
+    }
+
+    fn channels(&self) -> u8 {
+        self.streaminfo.channels as u8
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, claxon::Error> {
+        let mut cursor = 0;
+
+        while cursor < buf.len() {
+            if self.remaining() > 0 {
+                // copy from buffer
+                let len = min(self.remaining(), buf.len() - cursor);
+
+                buf[cursor..(len + cursor)]
+                    .copy_from_slice(&self.buffer[self.buffer_cursor..(self.buffer_cursor + len)]);
+
+                // advance buffer cursor
+                self.buffer_cursor += len;
+
+                cursor += len;
+            } else if self.next_block()?.is_none() {
+                break;
+            }
+        }
+
+        Ok(cursor)
+    }
+
+    /// Seeks the decoder.
+    ///
+    /// FLAC frames can't be found without reading the stream, so this jumps
+    /// to the closest point of the SEEKTABLE before `position` and decodes
+    /// from there. Files without a SEEKTABLE are decoded from the start,
+    /// which gets slow for long files.
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        let offset = self
+            .seek_points
+            .iter()
+            .rev()
+            .find(|(frame, _)| *frame <= position as u64)
+            .map_or(0, |(_, offset)| *offset);
+
+        let mut cursor = Cursor::new(self.source.clone());
+        cursor.set_position(self.frames_start + offset);
+        self.stream = FrameReader::new(cursor);
+
+        self.buffer.clear();
+        self.buffer_cursor = 0;
+
+        let channels = self.channels() as usize;
+
+        while let Some(time) = self.next_block()? {
+            let frames = (self.buffer.len() / channels) as u64;
+
+            if time + frames > position as u64 {
+                // skip what was decoded before `position`
+                let skip = position.saturating_sub(time as usize) * channels;
+                self.buffer_cursor = min(skip, self.buffer.len());
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads where the frames of a FLAC file start, and the points of its
+/// SEEKTABLE if it has one.
+///
+/// Returns `None` if the metadata blocks run past the end of the file.
+fn read_flac_seek_table(bytes: &[u8]) -> Option<(u64, Vec<(u64, u64)>)> {
+    /// The type of a SEEKTABLE metadata block.
+    const SEEKTABLE: u8 = 3;
+    /// The length of a seek point, in bytes.
+    const SEEK_POINT_LEN: usize = 18;
+
+    // skip "fLaC"
+    let mut cursor = 4;
+    let mut seek_points = Vec::new();
+
+    loop {
+        let header = bytes.get(cursor..(cursor + 4))?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+        cursor += 4;
+        let data = bytes.get(cursor..(cursor + len))?;
+        cursor += len;
+
+        if block_type == SEEKTABLE {
+            for point in data.chunks_exact(SEEK_POINT_LEN) {
+                let frame = u64::from_be_bytes(point[0..8].try_into().ok()?);
+                let offset = u64::from_be_bytes(point[8..16].try_into().ok()?);
+
+                // placeholder points are all ones
+                if frame != u64::MAX {
+                    seek_points.push((frame, offset));
+                }
+            }
+        }
+
+        if is_last {
+            break;
+        }
+    }
+
+    seek_points.sort_unstable();
+
+    Some((cursor as u64, seek_points))
+}
+
+/// A [`Source`] that plays a [`DecodedSound`].
+///
+/// Since the sound is already decoded, this only copies samples.
+pub struct DecodedSource {
+    sound: DecodedSound,
+    cursor: usize,
+}
+
+impl DecodedSource {
+    /// Creates a new `DecodedSource`.
+    pub fn new(sound: DecodedSound) -> DecodedSource {
+        DecodedSource { sound, cursor: 0 }
+    }
+}
+
+impl Source for DecodedSource {
+    type Error = Infallible;
+
+    fn sample_rate(&self) -> u32 {
+        self.sound.sample_rate
+    }
+
+    fn channels(&self) -> u8 {
+        self.sound.channels()
+    }
+
+    fn sample(&mut self, buf: &mut [i16]) -> Result<usize, Self::Error> {
+        let samples = &self.sound.samples[self.cursor..];
+        let len = min(samples.len(), buf.len());
+
+        buf[..len].copy_from_slice(&samples[..len]);
+        self.cursor += len;
+
+        Ok(len)
+    }
+
+    fn seek(&mut self, position: usize) -> Result<(), Self::Error> {
+        let cursor = position * self.channels() as usize;
+        self.cursor = min(cursor, self.sound.samples.len());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::fixtures::{flac, impulse, peak_frame, wav, wav_raw, WAV_FLOAT, WAV_PCM};
+
+    /// Reads `source` to the end.
+    fn read_all<S: Source>(source: &mut S) -> Vec<i16>
+    where
+        S::Error: Debug,
+    {
+        let mut samples = Vec::new();
+        let mut buf = [0; 1000];
+
+        loop {
+            let len = source.sample(&mut buf).unwrap();
+
+            if len == 0 {
+                return samples;
+            }
+
+            samples.extend_from_slice(&buf[..len]);
+        }
+    }
+
+    #[test]
+    fn resampler_seek_lands_on_position() {
+        let decoder = WavDecoder::new(wav(1, 44_100, &impulse(4410, 1000))).unwrap();
+        let mut resampler = Resampler::new(decoder, 48_000).unwrap();
+
+        // play a little first, so the seek has to reset the resampler
+        resampler.sample(&mut [0; 2000]).unwrap();
+        resampler.seek(500).unwrap();
+
+        // 1000 frames at 44.1kHz is 1088.4 frames at 48kHz
+        let samples = read_all(&mut resampler);
+        assert!((588..=589).contains(&peak_frame(&samples, 1)));
+    }
+
+    #[test]
+    fn time_stretch_goes_back_to_a_passthrough() {
+        let ramp = (0..16_000).collect::<Vec<i16>>();
+        let decoder = WavDecoder::new(wav(1, 8000, &ramp)).unwrap();
+        let mut stretch = TimeStretch::new(decoder);
+
+        stretch.set_rate(1.5);
+        stretch.sample(&mut [0; 2000]).unwrap();
+        stretch.set_rate(1.);
+
+        let samples = read_all(&mut stretch);
+        assert!(!stretch.engaged);
+
+        // after a grain to line back up, the ramp plays on to its end
+        let tail = &samples[stretch.grain_len..];
+        assert!(tail.windows(2).all(|w| w[1] == w[0] + 1));
+        assert_eq!(tail.last(), Some(&15_999));
+    }
+
+    /// A mono sine at `freq` Hz.
+    fn sine(sample_rate: u32, freq: f32, frames: usize) -> Vec<i16> {
+        (0..frames)
+            .map(|i| {
+                let phase = std::f32::consts::TAU * freq * i as f32 / sample_rate as f32;
+                (phase.sin() * 10_000.) as i16
+            })
+            .collect()
+    }
+
+    /// The gain of a [`Biquad`] on a sine at `freq` Hz, after it settles.
+    fn filter_gain(kind: FilterKind, cutoff: f32, freq: f32) -> f32 {
+        let samples = sine(48_000, freq, 48_000);
+        let mut filter = Biquad::new(WavDecoder::new(wav(1, 48_000, &samples)).unwrap(), kind);
+        filter.set_cutoff(Some(cutoff));
+
+        let filtered = read_all(&mut filter);
+        let peak = |samples: &[i16]| samples.iter().map(|s| s.unsigned_abs()).max().unwrap();
+
+        peak(&filtered[24_000..]) as f32 / peak(&samples[24_000..]) as f32
+    }
+
+    /// A mono `.wav` of `frames` frames of a constant 10000.
+    fn constant(sample_rate: u32, frames: usize) -> WavDecoder {
+        WavDecoder::new(wav(1, sample_rate, &vec![10_000; frames])).unwrap()
+    }
+
+    #[test]
+    fn low_pass_is_3db_down_at_the_cutoff() {
+        assert!(filter_gain(FilterKind::LowPass, 1000., 200.) > 0.95);
+        assert!((filter_gain(FilterKind::LowPass, 1000., 1000.) - FRAC_1_SQRT_2).abs() < 0.01);
+        assert!(filter_gain(FilterKind::LowPass, 1000., 8000.) < 0.02);
+    }
+
+    #[test]
+    fn high_pass_is_3db_down_at_the_cutoff() {
+        assert!(filter_gain(FilterKind::HighPass, 1000., 125.) < 0.02);
+        assert!((filter_gain(FilterKind::HighPass, 1000., 1000.) - FRAC_1_SQRT_2).abs() < 0.01);
+        assert!(filter_gain(FilterKind::HighPass, 1000., 8000.) > 0.95);
+    }
+
+    #[test]
+    fn linear_fade_lands_on_its_target() {
+        let mut fade = Fade::new(constant(1000, 200));
+        fade.fade_to(0., Duration::from_millis(100), FadeCurve::Linear);
+
+        let samples = read_all(&mut fade);
+
+        assert_eq!(samples[0], 9900);
+        assert_eq!(samples[49], 5000);
+        assert!(samples[99..].iter().all(|s| *s == 0));
+        assert!(!fade.is_fading());
+    }
+
+    #[test]
+    fn equal_power_fade_follows_a_quarter_sine() {
+        let mut fade = Fade::new(constant(1000, 200));
+        fade.set_gain(0.);
+        fade.fade_to(1., Duration::from_millis(100), FadeCurve::EqualPower);
+
+        let samples = read_all(&mut fade);
+
+        // sin(pi / 4) halfway through
+        assert!((samples[49] - 7071).abs() <= 1);
+        assert!(samples[..49].windows(2).all(|w| w[0] < w[1]));
+        assert!(samples[99..].iter().all(|s| *s == 10_000));
+    }
+
+    #[test]
+    fn reverb_rings_after_the_first_comb() {
+        let decoder = WavDecoder::new(wav(1, 44_100, &impulse(20_000, 0))).unwrap();
+        let mut reverb = Reverb::new(decoder);
+        reverb.set_mix(1.);
+
+        let samples = read_all(&mut reverb);
+        let first = samples.iter().position(|s| *s != 0);
+
+        assert_eq!(first, Some(REVERB_COMB_DELAYS[0]));
+        assert!(samples[15_000..].iter().any(|s| *s != 0));
+    }
+
+    #[test]
+    fn ducking_follows_the_key() {
+        let mut ducking = Ducking::new(constant(1000, 4000));
+        ducking.set_depth(0.25);
+
+        // too quiet to duck
+        ducking.set_key_level(DUCKING_THRESHOLD / 2.);
+        let mut buf = [0; 100];
+        ducking.sample(&mut buf).unwrap();
+        assert!(buf.iter().all(|s| *s == 10_000));
+
+        // ducks within a few attack times
+        ducking.set_key_level(0.5);
+        ducking.sample(&mut buf).unwrap();
+        assert!(buf[0] < 10_000);
+        assert_eq!(buf[99], 2500);
+
+        // recovers within a few release times
+        ducking.set_key_level(0.);
+        let samples = read_all(&mut ducking);
+        assert!(samples[..100].windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(samples.last(), Some(&10_000));
+    }
+
+    /// A mono ramp of 1000 frames, looping from frame 100 to 300.
+    fn looped_ramp() -> Looped<WavDecoder> {
+        let ramp = (0..1000).collect::<Vec<i16>>();
+        let decoder = WavDecoder::new(wav(1, 44_100, &ramp)).unwrap();
+        let region = LoopRegion {
+            start: 100,
+            end: Some(300),
+        };
+
+        Looped::new(decoder, Some(region), Arc::default())
+    }
+
+    #[test]
+    fn looped_wraps_to_the_loop_start() {
+        let mut looped = looped_ramp();
+        let mut buf = [0; 700];
+
+        assert_eq!(looped.sample(&mut buf).unwrap(), 700);
+
+        // LOOPSTART + LOOPLENGTH - 1 is followed by LOOPSTART
+        assert_eq!(buf[299..=300], [299, 100]);
+        assert_eq!(buf[499..=500], [299, 100]);
+        assert_eq!(looped.loop_count(), 2);
+    }
+
+    #[test]
+    fn looped_seek_past_the_end_wraps() {
+        let mut looped = looped_ramp();
+        let mut buf = [0; 1];
+
+        // 300 frames to the end, then two and a quarter loops
+        looped.seek(750).unwrap();
+        looped.sample(&mut buf).unwrap();
+
+        assert_eq!(buf, [150]);
+        assert_eq!(looped.loop_count(), 3);
+
+        // before the end, seeks are as is
+        looped.seek(250).unwrap();
+        looped.sample(&mut buf).unwrap();
+
+        assert_eq!(buf, [250]);
+        assert_eq!(looped.loop_count(), 0);
+    }
+
+    /// A stereo ramp of `frames` frames, rising on the left and falling on
+    /// the right.
+    fn stereo_ramp(frames: i16) -> Vec<i16> {
+        (0..frames).flat_map(|i| [i, -i]).collect()
+    }
+
+    #[test]
+    fn wav_decodes_and_seeks() {
+        let ramp = stereo_ramp(1000);
+        let mut decoder = WavDecoder::new(wav(2, 44_100, &ramp)).unwrap();
+
+        assert_eq!(decoder.frames(), 1000);
+        assert_eq!(read_all(&mut decoder), ramp);
+
+        decoder.seek(600).unwrap();
+        assert_eq!(read_all(&mut decoder), ramp[1200..]);
+    }
+
+    #[test]
+    fn wav_24_bit_keeps_the_top_bits() {
+        let data = [0x12_3456, -0x12_3456, 0x7f_ffff, -0x80_0000]
+            .iter()
+            .flat_map(|s: &i32| s.to_le_bytes().into_iter().take(3))
+            .collect::<Vec<_>>();
+        let mut decoder = WavDecoder::new(wav_raw(WAV_PCM, 1, 44_100, 24, &data)).unwrap();
+
+        assert_eq!(
+            read_all(&mut decoder),
+            [0x1234, -0x1235, i16::MAX, i16::MIN]
+        );
+    }
+
+    #[test]
+    fn wav_float_is_scaled_and_clamped() {
+        let data = [0., 0.5, -0.5, -1., 2.]
+            .iter()
+            .flat_map(|s: &f32| s.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut decoder = WavDecoder::new(wav_raw(WAV_FLOAT, 1, 44_100, 32, &data)).unwrap();
+
+        assert_eq!(
+            read_all(&mut decoder),
+            [0, 16_384, -16_384, i16::MIN, i16::MAX]
+        );
+    }
+
+    #[test]
+    fn flac_decodes_and_seeks() {
+        let ramp = stereo_ramp(1000);
+        let mut decoder = FlacDecoder::new(flac(2, 44_100, 256, &ramp, None)).unwrap();
+
+        assert_eq!(read_all(&mut decoder), ramp);
+
+        // in the middle of a block, without a SEEKTABLE
+        decoder.seek(600).unwrap();
+        assert_eq!(read_all(&mut decoder), ramp[1200..]);
+    }
+
+    #[test]
+    fn flac_seeks_with_the_seek_table() {
+        let ramp = stereo_ramp(2048);
+        let mut source = flac(2, 44_100, 256, &ramp, Some(4));
+
+        // break the first block, so only a seek that skips it can work
+        let mut bytes = source.bytes.to_vec();
+        let (frames_start, _) = read_flac_seek_table(&bytes).unwrap();
+        // past the frame header and the subframe header
+        bytes[frames_start as usize + 8 + 1] ^= 0xff;
+        source.bytes = bytes.into();
+
+        let mut decoder = FlacDecoder::new(source).unwrap();
+        assert!(decoder.sample(&mut [0; 2]).is_err());
+
+        decoder.seek(1500).unwrap();
+        assert_eq!(read_all(&mut decoder), ramp[3000..]);
+    }
 }
@@ -0,0 +1,212 @@
+//! Waveform overviews of audio, for editors and previews.
+
+use bevy::prelude::*;
+
+use std::ops::Range;
+use std::time::Duration;
+
+use super::asset::AudioSource;
+use super::source::{DecodeError, Decoder, Source};
+
+/// How many frames each peak of the finest level of a [`Waveform`] covers.
+pub const WAVEFORM_BASE_FRAMES: u32 = 256;
+
+/// How many samples are decoded at once when generating a [`Waveform`].
+const DECODE_BUFFER_LEN: usize = 8192;
+
+/// A waveform overview of some audio.
+///
+/// This is a table of min/max peaks at several resolutions, so a waveform
+/// can be drawn at any zoom without touching the audio. The finest level
+/// has a peak every [`WAVEFORM_BASE_FRAMES`] frames, and each level after it
+/// has half as many peaks as the one before. Each peak is the lowest and
+/// highest sample of any channel, so the channels are not mixed down.
+///
+/// To load one with an [`AudioSource`], load the
+/// [`WAVEFORM_LABEL`](super::WAVEFORM_LABEL) of a file with
+/// [`AudioLoaderSettings::waveform`](super::AudioLoaderSettings::waveform):
+/// ```ignore
+/// let waveform: Handle<Waveform> = asset_server.load_with_settings(
+///     "songs/song.ogg#waveform",
+///     |settings: &mut AudioLoaderSettings| settings.waveform = true,
+/// );
+/// ```
+#[derive(Asset, Clone, Debug, TypePath)]
+pub struct Waveform {
+    /// The sample rate of the audio.
+    pub sample_rate: u32,
+    /// The length of the audio, in frames.
+    pub frames: u64,
+    /// The levels of the waveform, from finest to coarsest.
+    pub levels: Vec<WaveformLevel>,
+}
+
+/// One resolution of a [`Waveform`].
+#[derive(Clone, Debug)]
+pub struct WaveformLevel {
+    /// How many frames each peak covers.
+    pub frames_per_peak: u32,
+    /// The peaks, in order.
+    pub peaks: Vec<Peak>,
+}
+
+/// The lowest and highest sample over a stretch of audio.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Peak {
+    pub min: i16,
+    pub max: i16,
+}
+
+impl Peak {
+    /// A peak that covers nothing, which any sample widens.
+    const EMPTY: Peak = Peak {
+        min: i16::MAX,
+        max: i16::MIN,
+    };
+
+    /// Widens the peak to cover `other`.
+    fn merge(self, other: Peak) -> Peak {
+        Peak {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    /// Checks if the peak covers nothing.
+    fn is_empty(&self) -> bool {
+        self.min > self.max
+    }
+}
+
+impl Waveform {
+    /// Decodes `source` as fast as possible and builds its waveform.
+    ///
+    /// This doesn't need an audio device, and can take a while for long
+    /// songs, so it should not be called on the main thread.
+    pub fn generate(source: AudioSource) -> Result<Waveform, DecodeError> {
+        let mut decoder = Decoder::new(source)?;
+
+        let sample_rate = decoder.sample_rate();
+        let channels = decoder.channels() as usize;
+
+        let mut peaks = Vec::new();
+        let mut current = Peak::EMPTY;
+        let mut current_frames = 0;
+        let mut frames = 0u64;
+
+        let mut buf = vec![0; DECODE_BUFFER_LEN - DECODE_BUFFER_LEN % channels];
+
+        loop {
+            let len = decoder.sample(&mut buf)?;
+
+            if len == 0 {
+                break;
+            }
+
+            for frame in buf[..len].chunks_exact(channels) {
+                for sample in frame {
+                    current = current.merge(Peak {
+                        min: *sample,
+                        max: *sample,
+                    });
+                }
+
+                current_frames += 1;
+
+                if current_frames == WAVEFORM_BASE_FRAMES {
+                    peaks.push(current);
+                    current = Peak::EMPTY;
+                    current_frames = 0;
+                }
+            }
+
+            frames += (len / channels) as u64;
+        }
+
+        if !current.is_empty() {
+            peaks.push(current);
+        }
+
+        let mut levels = vec![WaveformLevel {
+            frames_per_peak: WAVEFORM_BASE_FRAMES,
+            peaks,
+        }];
+
+        // halve until a single peak covers everything
+        while let Some(level) = levels.last().filter(|l| l.peaks.len() > 1) {
+            let next = WaveformLevel {
+                frames_per_peak: level.frames_per_peak * 2,
+                peaks: level
+                    .peaks
+                    .chunks(2)
+                    .map(|pair| pair.iter().fold(Peak::EMPTY, |a, b| a.merge(*b)))
+                    .collect(),
+            };
+
+            levels.push(next);
+        }
+
+        Ok(Waveform {
+            sample_rate,
+            frames,
+            levels,
+        })
+    }
+
+    /// The length of the audio.
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.frames as f64 / self.sample_rate as f64)
+    }
+
+    /// Returns the coarsest level with at least one peak every
+    /// `frames_per_pixel` frames.
+    pub fn level(&self, frames_per_pixel: f64) -> &WaveformLevel {
+        self.levels
+            .iter()
+            .rev()
+            .find(|level| level.frames_per_peak as f64 <= frames_per_pixel)
+            .unwrap_or(&self.levels[0])
+    }
+
+    /// Returns a peak for each of `columns` columns spanning `range` of the
+    /// audio, ready to be drawn.
+    ///
+    /// Columns past the end of the audio are silent.
+    pub fn peaks(&self, range: Range<Duration>, columns: usize) -> Vec<Peak> {
+        if columns == 0 {
+            return Vec::new();
+        }
+
+        let sample_rate = self.sample_rate as f64;
+        let start = range.start.as_secs_f64() * sample_rate;
+        let end = range.end.as_secs_f64() * sample_rate;
+        let frames_per_column = (end - start).max(0.) / columns as f64;
+
+        let level = self.level(frames_per_column);
+        let frames_per_peak = level.frames_per_peak as f64;
+
+        (0..columns)
+            .map(|column| {
+                let from = start + column as f64 * frames_per_column;
+                let to = from + frames_per_column;
+
+                let first = (from / frames_per_peak).floor().max(0.) as usize;
+                // a column always covers at least one peak
+                let last = ((to / frames_per_peak).ceil() as usize).max(first + 1);
+
+                let peak = level
+                    .peaks
+                    .get(first..last.min(level.peaks.len()))
+                    .unwrap_or_default()
+                    .iter()
+                    .fold(Peak::EMPTY, |a, b| a.merge(*b));
+
+                if peak.is_empty() {
+                    Peak::default()
+                } else {
+                    peak
+                }
+            })
+            .collect()
+    }
+}
@@ -1,17 +1,18 @@
 //! Rhythm and beatmap assets.
 
-use bevy::asset::{io::Reader, AssetLoader, AsyncReadExt, LoadContext};
+use bevy::asset::{io::Reader, AssetLoader, AssetPath, AsyncReadExt, LoadContext};
 use bevy::prelude::*;
 use bevy::utils::BoxedFuture;
 
 use serde::{Deserialize, Serialize};
 
 use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt::{self, Display, Formatter};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use crate::audio::AudioSource;
+use crate::audio::{AudioLoadMode, AudioLoaderSettings, AudioSource, DecodedSound, DECODED_LABEL};
 
 /// An asset loader for beatmaps.
 #[derive(Default)]
@@ -39,6 +40,30 @@ impl AssetLoader for BeatmapLoader {
             let handle = load_context.load::<AudioSource>(data.song.path.clone());
             data.song.handle = handle;
 
+            // load stems
+            for stem in data.song.stems.iter_mut() {
+                stem.handle = load_context.load::<AudioSource>(stem.path.clone());
+            }
+
+            // load hitsounds decoded, so they play without delay
+            let paths = data
+                .hitsounds
+                .default
+                .iter()
+                .chain(data.hitsounds.lanes.values())
+                .chain(data.notes.iter().filter_map(|n| n.keysound.as_ref()))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for path in paths {
+                let handle = load_context.load_with_settings(
+                    AssetPath::from(path.clone()).with_label(DECODED_LABEL),
+                    |settings: &mut AudioLoaderSettings| settings.mode = AudioLoadMode::Decoded,
+                );
+
+                data.hitsounds.handles.insert(path, handle);
+            }
+
             // sort notes
             data.notes
                 .sort_unstable_by(|a, b| a.partial_cmp(b).expect("got NaN as beat for note"));
@@ -99,10 +124,30 @@ pub struct Beatmap {
     pub lane_count: u32,
     /// Song definitions.
     pub song: BeatmapSong,
+    /// Hitsound definitions.
+    #[serde(default)]
+    pub hitsounds: BeatmapHitsounds,
     notes: Vec<BeatmapNote>,
 }
 
 impl Beatmap {
+    /// Returns the hitsound of a note in `lane`.
+    ///
+    /// `note` is the index of the note in [`Beatmap::notes`], if there is
+    /// one. The keysound of the note is picked first, then the sound of the
+    /// lane, then the default sound.
+    pub fn hitsound(&self, lane: u32, note: Option<usize>) -> Option<&Handle<DecodedSound>> {
+        let keysound = note
+            .and_then(|i| self.notes.get(i))
+            .and_then(|n| n.keysound.as_deref());
+
+        let path = keysound
+            .or_else(|| self.hitsounds.lanes.get(&lane).map(PathBuf::as_path))
+            .or(self.hitsounds.default.as_deref())?;
+
+        self.hitsounds.handle(path)
+    }
+
     /// The actual beatmap, a-la where all the notes are placed.
     ///
     /// The notes returned are sorted by the beat they start on. To modify
@@ -124,6 +169,12 @@ pub struct BeatmapSong {
     pub bpm: u32,
     /// The offset of where the song actually starts, in milliseconds.
     pub offset: u32,
+    /// Stems played on top of the song, locked to it.
+    ///
+    /// The song is the clock the stems follow, so it is usually the backing
+    /// track and the stems the parts that react to the player.
+    #[serde(default)]
+    pub stems: Vec<BeatmapStem>,
 }
 
 impl BeatmapSong {
@@ -133,6 +184,68 @@ impl BeatmapSong {
     }
 }
 
+/// A single stem of a [`BeatmapSong`], like the drums or the lead.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BeatmapStem {
+    /// The path to the stem, relative to the beatmap's package.
+    pub path: PathBuf,
+    /// A handle to the stem.
+    #[serde(skip)]
+    pub handle: Handle<AudioSource>,
+    /// What happens to the stem while the player misses notes.
+    #[serde(default)]
+    pub on_miss: StemMiss,
+}
+
+/// What happens to a stem while the player misses notes.
+///
+/// The stem comes back on the next hit.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub enum StemMiss {
+    /// The stem plays through misses.
+    #[default]
+    Keep,
+    /// The stem is silent.
+    Mute,
+    /// The stem is muffled with a low-pass filter.
+    Muffle,
+}
+
+/// A beatmap's hitsound definitions.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BeatmapHitsounds {
+    /// When hitsounds are played.
+    #[serde(default)]
+    pub trigger: HitsoundTrigger,
+    /// The path to the sound played on every lane, relative to the
+    /// beatmap's package.
+    #[serde(default)]
+    pub default: Option<PathBuf>,
+    /// Paths to sounds played on specific lanes, by lane number.
+    #[serde(default)]
+    pub lanes: HashMap<u32, PathBuf>,
+    /// Handles to every hitsound and keysound, by path.
+    #[serde(skip)]
+    pub handles: HashMap<PathBuf, Handle<DecodedSound>>,
+}
+
+impl BeatmapHitsounds {
+    /// Returns the handle to the sound at `path`.
+    pub fn handle(&self, path: &Path) -> Option<&Handle<DecodedSound>> {
+        self.handles.get(path)
+    }
+}
+
+/// When hitsounds are played.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub enum HitsoundTrigger {
+    /// Every key press on a lane plays a hitsound, even if it misses.
+    #[default]
+    KeyDown,
+    /// Only notes that are hit play a hitsound.
+    Judgement,
+}
+
 /// A single placement of a note.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct BeatmapNote {
@@ -141,6 +254,10 @@ pub struct BeatmapNote {
     end_beat: Option<f32>,
     /// What lane the note appears in.
     pub lane: u32,
+    /// The path to a sound played instead of the lane's hitsound, relative
+    /// to the beatmap's package.
+    #[serde(default)]
+    pub keysound: Option<PathBuf>,
 }
 
 impl BeatmapNote {
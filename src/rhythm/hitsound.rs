@@ -0,0 +1,98 @@
+//! Hitsounds and keysounds.
+
+use bevy::prelude::*;
+
+use crate::audio::{AudioBus, SoundPlayer};
+
+use super::{
+    asset::{Beatmap, HitsoundTrigger},
+    input::{KeyEvent, KeyEventType},
+    judgement::JudgementEvent,
+    note::{Lane, Note},
+};
+
+/// Plays hitsounds on key presses, for beatmaps with
+/// [`HitsoundTrigger::KeyDown`].
+///
+/// The keysound of the next note in the lane is played, if it has one.
+pub fn play_key_hitsounds(
+    beatmaps: Query<&Handle<Beatmap>>,
+    lanes: Query<(&Lane, &Parent)>,
+    notes: Query<&Note>,
+    beatmap_assets: Res<Assets<Beatmap>>,
+    mut key_events: EventReader<KeyEvent>,
+    player: SoundPlayer,
+) {
+    for key in key_events.read() {
+        if key.kind != KeyEventType::Down {
+            continue;
+        }
+
+        let Ok((lane, parent)) = lanes.get(key.lane) else {
+            continue;
+        };
+
+        let Some(beatmap) = beatmaps
+            .get(parent.get())
+            .ok()
+            .and_then(|b| beatmap_assets.get(b))
+        else {
+            continue;
+        };
+
+        if beatmap.hitsounds.trigger != HitsoundTrigger::KeyDown {
+            continue;
+        }
+
+        let note = lane
+            .next_note()
+            .and_then(|n| notes.get(n).ok())
+            .map(Note::index);
+
+        if let Some(sound) = beatmap.hitsound(lane.number(), note) {
+            player.play(sound, AudioBus::Sfx);
+        }
+    }
+}
+
+/// Plays hitsounds on hit notes, for beatmaps with
+/// [`HitsoundTrigger::Judgement`].
+pub fn play_judgement_hitsounds(
+    beatmaps: Query<&Handle<Beatmap>>,
+    lanes: Query<(&Lane, &Parent)>,
+    notes: Query<(&Note, &Parent)>,
+    beatmap_assets: Res<Assets<Beatmap>>,
+    mut judgement_events: EventReader<JudgementEvent>,
+    player: SoundPlayer,
+) {
+    for judgement in judgement_events.read() {
+        if judgement.offset.is_none() {
+            // misses are silent
+            continue;
+        }
+
+        let Ok((note, note_parent)) = notes.get(judgement.note) else {
+            continue;
+        };
+
+        let Ok((lane, lane_parent)) = lanes.get(note_parent.get()) else {
+            continue;
+        };
+
+        let Some(beatmap) = beatmaps
+            .get(lane_parent.get())
+            .ok()
+            .and_then(|b| beatmap_assets.get(b))
+        else {
+            continue;
+        };
+
+        if beatmap.hitsounds.trigger != HitsoundTrigger::Judgement {
+            continue;
+        }
+
+        if let Some(sound) = beatmap.hitsound(lane.number(), Some(note.index())) {
+            player.play(sound, AudioBus::Sfx);
+        }
+    }
+}
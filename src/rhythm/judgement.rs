@@ -18,6 +18,8 @@ pub struct JudgementEvent {
     /// The offset in seconds. A positive offset means the note press was too
     /// early, and a negative means the press was too late.
     ///
+    /// This is in real time, so it is independent of the playback rate.
+    ///
     /// If the note was missed, this is `None`.
     pub offset: Option<f32>,
 }
@@ -54,7 +56,9 @@ pub fn create_judgements(
         let note_position = rhythm.beat_position(next_note.beat());
         let input_position = key.timestamp;
 
-        let diff = note_position.as_secs_f32() - input_position.as_secs_f32();
+        // judge in real time so windows don't change with the playback rate
+        let diff =
+            (note_position.as_secs_f32() - input_position.as_secs_f32()) / rhythm.context().rate();
 
         let window_max = beatmap.note_window.as_secs_f32();
 
@@ -108,7 +112,7 @@ pub fn create_dropped_judgements(
 
                 if let Some(offset) = current_position.checked_sub(note_position) {
                     // offset cannot be greater than window
-                    offset > beatmap.note_window
+                    rhythm.real_duration(offset) > beatmap.note_window
                 } else {
                     false
                 }
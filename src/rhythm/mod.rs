@@ -1,11 +1,15 @@
 //! Higher level rhythm tracking.
 
 pub mod asset;
+pub mod hitsound;
 pub mod input;
 pub mod judgement;
 pub mod note;
 pub mod render;
+pub mod stem;
 
+use bevy::asset::LoadState;
+use bevy::ecs::system::Command;
 use bevy::prelude::*;
 use bevy::transform::TransformSystem;
 
@@ -14,7 +18,7 @@ use bevy_asset_loader::prelude::*;
 use std::time::Duration;
 
 use crate::{
-    audio::{AudioControl, AudioSource},
+    audio::{AudioBundle, AudioControl, AudioSource, PlaybackState},
     effect::{AnimationFrames, AnimationTimer},
     rhythm::input::LaneInputKeyboard,
     GameState,
@@ -64,6 +68,23 @@ impl Plugin for RhythmPlugin {
                     .in_set(RhythmSystem::Judgement)
                     .after(RhythmSystem::Input),
             )
+            .add_systems(
+                PreUpdate,
+                hitsound::play_key_hitsounds
+                    .in_set(RhythmSystem::Hitsound)
+                    .after(RhythmSystem::Input),
+            )
+            .add_systems(
+                Update,
+                hitsound::play_judgement_hitsounds
+                    .in_set(RhythmSystem::Hitsound)
+                    .after(RhythmSystem::Judgement),
+            )
+            .add_systems(
+                Update,
+                stem::update_stems_on_judgement.after(RhythmSystem::Judgement),
+            )
+            .add_systems(PostUpdate, stem::sync_stems)
             .add_systems(
                 Update,
                 spawn_hit_effects
@@ -103,6 +124,8 @@ pub enum RhythmSystem {
     Judgement,
     /// Create input events.
     Input,
+    /// Plays hitsounds.
+    Hitsound,
     /// Spawns related sprites.
     SpawnSprites,
     /// Updates note positions, placements and loading.
@@ -178,6 +201,51 @@ impl Default for BeatmapInstance {
 #[derive(Clone, Copy, Component, Default, Debug)]
 pub struct MainTrack;
 
+/// A command that crossfades from the [`MainTrack`] to a new track, like
+/// from overworld music into battle music.
+///
+/// The new track is spawned as the `MainTrack`, and fades in as soon as it
+/// starts while the old track fades out. The old track loses its `MainTrack`
+/// and is stopped once silent, but its entity is left for the caller to
+/// clean up. If there is no `MainTrack`, the new track plays right away.
+/// ```ignore
+/// commands.add(CrossfadeMainTrack {
+///     source: asset_server.load("music/battle.ogg"),
+///     duration: Duration::from_secs(2),
+/// });
+/// ```
+#[derive(Clone, Debug)]
+pub struct CrossfadeMainTrack {
+    /// The new track.
+    pub source: Handle<AudioSource>,
+    /// How long the crossfade takes.
+    pub duration: Duration,
+}
+
+impl Command for CrossfadeMainTrack {
+    fn apply(self, world: &mut World) {
+        let mut main_track = world.query_filtered::<(Entity, &AudioControl), With<MainTrack>>();
+
+        let actl = match main_track.get_single(world) {
+            Ok((entity, from)) => {
+                let actl = AudioControl::crossfade(from, self.duration);
+                world.entity_mut(entity).remove::<MainTrack>();
+                actl
+            }
+            Err(_) => AudioControl::default(),
+        };
+
+        world.spawn((
+            AudioBundle {
+                source: self.source,
+                actl,
+                ..Default::default()
+            },
+            MainTrack,
+        ));
+    }
+}
+
 /// The rhythm clock, a more high level abstraction over rhythm timings.
 ///
 /// Can be accessed through the [`Time`] resource. For accessor and mutator
@@ -202,6 +270,8 @@ pub struct Rhythm {
 
     position: Duration,
     is_interpolating: bool,
+
+    rate: f32,
 }
 
 impl Rhythm {
@@ -216,6 +286,8 @@ impl Rhythm {
 
             position: Duration::ZERO,
             is_interpolating: false,
+
+            rate: 1.,
         }
     }
 
@@ -233,6 +305,14 @@ impl Rhythm {
     pub fn offset(&self) -> Duration {
         self.offset
     }
+
+    /// Returns the playback rate of the current song.
+    ///
+    /// The clock advances `rate` seconds of song for every second of real
+    /// time.
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
 }
 
 impl Default for Rhythm {
@@ -260,6 +340,13 @@ pub trait RhythmExt {
     /// Panics if `beat` is negative.
     fn beat_position(&self, beat: f32) -> Duration;
 
+    /// Converts a duration of the song into how long it actually takes to
+    /// play, accounting for the playback rate.
+    ///
+    /// Judgement windows are in real time, so they should be compared
+    /// against song durations converted with this.
+    fn real_duration(&self, song_duration: Duration) -> Duration;
+
     /// The beat number that the song is on.
     ///
     /// This returns a float that represents the current beat, with `0.0` being
@@ -290,6 +377,10 @@ impl RhythmExt for Time<Rhythm> {
         ctx.crotchet.mul_f32(beat) + ctx.offset
     }
 
+    fn real_duration(&self, song_duration: Duration) -> Duration {
+        song_duration.div_f32(self.context().rate)
+    }
+
     fn beat_number(&self) -> f32 {
         let elapsed = self.position().as_secs_f32();
         let ctx = self.context();
@@ -306,16 +397,37 @@ impl RhythmExt for Time<Rhythm> {
 
 fn spawn_beatmap(
     mut new_beatmaps: Query<
-        (Entity, &Handle<Beatmap>, &mut Handle<AudioSource>),
+        (
+            Entity,
+            &Handle<Beatmap>,
+            &mut Handle<AudioSource>,
+            &AudioControl,
+        ),
         Without<BeatmapInstance>,
     >,
     beatmaps: Res<Assets<Beatmap>>,
+    audio_sources: Res<Assets<AudioSource>>,
+    asset_server: Res<AssetServer>,
     image_assets: Res<ImageAssets>,
     mut rhythm: ResMut<Time<Rhythm>>,
     mut commands: Commands,
 ) {
-    for (entity, beatmap_handle, mut audio_handle) in new_beatmaps.iter_mut() {
+    for (entity, beatmap_handle, mut audio_handle, actl) in new_beatmaps.iter_mut() {
         if let Some(beatmap) = beatmaps.get(beatmap_handle) {
+            // stems only start with the song if they are all sent together;
+            // ones that failed to load are left out
+            let stems_loaded = beatmap.song.stems.is_empty()
+                || std::iter::once(&beatmap.song.handle)
+                    .chain(beatmap.song.stems.iter().map(|s| &s.handle))
+                    .all(|h| {
+                        audio_sources.contains(h)
+                            || asset_server.load_state(h.id()) == LoadState::Failed
+                    });
+
+            if !stems_loaded {
+                continue;
+            }
+
             // update audio handle
             *audio_handle = beatmap.song.handle.clone();
 
@@ -364,6 +476,13 @@ fn spawn_beatmap(
                     });
             }
 
+            // spawn stems, which would wait forever on a song that failed
+            if asset_server.load_state(beatmap.song.handle.id()) != LoadState::Failed {
+                commands.entity(entity).with_children(|parent| {
+                    stem::spawn_stems(parent, beatmap, actl, &asset_server)
+                });
+            }
+
             // instance beatmap
             commands.entity(entity).insert(BeatmapInstance::default());
 
@@ -439,14 +558,25 @@ fn spawn_notes(
     }
 }
 
+/// How far the audio can be from the rhythm clock before the clock stops
+/// interpolating and jumps to the audio.
+const RESYNC_THRESHOLD: Duration = Duration::from_millis(500);
+
 fn interpolate_rhythm_clock(
     main_track: Query<&AudioControl, With<MainTrack>>,
     time: Res<Time<Real>>,
     mut rhythm: ResMut<Time<Rhythm>>,
 ) {
     if let Ok(ctl) = main_track.get_single() {
-        let mut current_time = rhythm.elapsed().as_secs_f32();
+        if ctl.state() != PlaybackState::Playing {
+            // hold the clock still until the track plays again
+            rhythm.context_mut().is_interpolating = false;
+            rhythm.advance_by(Duration::ZERO);
+            return;
+        }
+
         let rhythm_ctx = rhythm.context_mut();
+        let mut current_time = rhythm_ctx.position.as_secs_f32();
 
         let Rhythm {
             timestamp: last_timestamp,
@@ -456,10 +586,19 @@ fn interpolate_rhythm_clock(
 
         // get next timestamp
         rhythm_ctx.timestamp = ctl.position();
+        rhythm_ctx.rate = ctl.rate();
+
+        // if the track was seeked, jump straight to the new position instead
+        // of slowly drifting to it
+        let drift = rhythm_ctx.timestamp.as_secs_f32() - last_position.as_secs_f32();
+
+        if drift.abs() > RESYNC_THRESHOLD.as_secs_f32() * rhythm_ctx.rate {
+            rhythm_ctx.is_interpolating = false;
+        }
 
         if rhythm_ctx.is_interpolating {
-            // interpolate time on clock
-            current_time += time.delta_seconds();
+            // interpolate time on clock; the song plays at `rate`
+            current_time += time.delta_seconds() * rhythm_ctx.rate;
 
             // if there is a time difference, adjust for the difference
             current_time += (rhythm_ctx.timestamp.as_secs_f32() - current_time) / 8.;
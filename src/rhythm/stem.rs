@@ -0,0 +1,166 @@
+//! Stems, played locked to the main track.
+
+use bevy::asset::LoadState;
+use bevy::prelude::*;
+
+use std::time::Duration;
+
+use crate::audio::{AudioBundle, AudioControl, FadeCurve, PlaybackState};
+
+use super::{
+    asset::{Beatmap, StemMiss},
+    judgement::JudgementEvent,
+    note::Note,
+    MainTrack,
+};
+
+/// How long a stem takes to drop out on a miss, or come back on a hit.
+const STEM_MISS_FADE: Duration = Duration::from_millis(80);
+
+/// The low-pass cutoff of a [`StemMiss::Muffle`] stem while missing, in Hz.
+const STEM_MISS_CUTOFF: f32 = 500.;
+
+/// How far a stem can be from the main track before it is seeked back.
+///
+/// Both timestamps are stored by the same mix, but one can be read before
+/// the mix stores it and the other after, so they can disagree by about a
+/// buffer even when the stem is locked.
+const STEM_RESYNC_THRESHOLD: Duration = Duration::from_millis(50);
+
+/// A stem of a beatmap's song.
+///
+/// Stems are spawned as children of the beatmap, and start on the same
+/// sample as the [`MainTrack`]. They follow its rate, pauses and seeks.
+#[derive(Clone, Copy, Component, Debug)]
+pub struct Stem {
+    on_miss: StemMiss,
+    missing: bool,
+}
+
+impl Stem {
+    /// What happens to the stem while the player misses notes.
+    pub fn on_miss(&self) -> StemMiss {
+        self.on_miss
+    }
+
+    /// Checks if the stem is dropped out for a miss.
+    pub fn is_missing(&self) -> bool {
+        self.missing
+    }
+
+    fn set_missing(&mut self, missing: bool, actl: &AudioControl) {
+        if self.missing == missing {
+            return;
+        }
+
+        self.missing = missing;
+
+        match self.on_miss {
+            StemMiss::Keep => (),
+            StemMiss::Mute => {
+                let to = if missing { 0. } else { 1. };
+                actl.fade(to, STEM_MISS_FADE, FadeCurve::Linear);
+            }
+            StemMiss::Muffle => {
+                actl.set_low_pass(missing.then_some(STEM_MISS_CUTOFF));
+            }
+        }
+    }
+}
+
+/// Spawns the stems of `beatmap`, scheduled on the start of `main_track`.
+///
+/// The stems and the main track have to be sent to the device in the same
+/// frame to start on the same sample, so every source should be loaded
+/// first. Stems that failed to load are skipped.
+pub(super) fn spawn_stems(
+    parent: &mut ChildBuilder,
+    beatmap: &Beatmap,
+    main_track: &AudioControl,
+    asset_server: &AssetServer,
+) {
+    for (i, stem) in beatmap.song.stems.iter().enumerate() {
+        if asset_server.load_state(stem.handle.id()) == LoadState::Failed {
+            error!("Failed to load stem \"{}\"", stem.path.display());
+            continue;
+        }
+
+        parent.spawn((
+            AudioBundle {
+                source: stem.handle.clone(),
+                actl: AudioControl::scheduled(main_track, Duration::ZERO),
+                ..Default::default()
+            },
+            Stem {
+                on_miss: stem.on_miss,
+                missing: false,
+            },
+            Name::new(format!("Stem {}", i)),
+        ));
+    }
+}
+
+/// Keeps stems playing with the main track.
+///
+/// Stems copy the rate and playback state of the main track, and are seeked
+/// back to it if they drift, like after the main track is seeked.
+pub fn sync_stems(
+    main_track: Query<&AudioControl, With<MainTrack>>,
+    stems: Query<(&AudioControl, &Parent), With<Stem>>,
+) {
+    for (actl, parent) in stems.iter() {
+        let Ok(main) = main_track.get(parent.get()) else {
+            continue;
+        };
+
+        if actl.rate() != main.rate() || actl.rate_mode() != main.rate_mode() {
+            actl.set_rate(main.rate(), main.rate_mode());
+        }
+
+        match (main.state(), actl.state()) {
+            (PlaybackState::Paused, PlaybackState::Playing) => actl.pause(),
+            (PlaybackState::Playing, PlaybackState::Paused) => actl.resume(),
+            (PlaybackState::Stopped, PlaybackState::Playing | PlaybackState::Paused) => actl.stop(),
+            (PlaybackState::Playing, PlaybackState::Playing) => {
+                // the audible positions are estimated separately, so they
+                // drift apart; the timestamps are exact
+                let sample_rate = main.sample_rate() as u128;
+                let threshold = STEM_RESYNC_THRESHOLD.as_nanos() * sample_rate / 1_000_000_000;
+                let timestamp = main.timestamp();
+
+                if timestamp.abs_diff(actl.timestamp()) as u128 > threshold {
+                    let nanos = timestamp as u128 * 1_000_000_000 / sample_rate;
+                    actl.seek(Duration::from_nanos(nanos as u64));
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Drops stems out on missed notes, and brings them back on hits.
+pub fn update_stems_on_judgement(
+    notes: Query<&Parent, With<Note>>,
+    lanes: Query<&Parent>,
+    mut stems: Query<(&mut Stem, &AudioControl, &Parent)>,
+    mut judgement_events: EventReader<JudgementEvent>,
+) {
+    for judgement in judgement_events.read() {
+        let Some(beatmap) = notes
+            .get(judgement.note)
+            .and_then(|lane| lanes.get(lane.get()))
+            .map(Parent::get)
+            .ok()
+        else {
+            continue;
+        };
+
+        let missing = judgement.offset.is_none();
+
+        for (mut stem, actl, parent) in stems.iter_mut() {
+            if parent.get() == beatmap {
+                stem.set_missing(missing, actl);
+            }
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Fixtures shared by the integration tests.
+
+// each test only uses some of these
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use rrpg::audio::{AudioFormat, AudioMetadata, AudioSource};
+
+/// Writes out the bytes of a 16-bit `.wav` of interleaved `samples` by hand.
+pub fn wav_bytes(channels: u16, sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+    let data_len = samples.len() as u32 * 2;
+
+    let mut bytes = Vec::new();
+    bytes.extend(b"RIFF");
+    bytes.extend((36 + data_len).to_le_bytes());
+    bytes.extend(b"WAVEfmt ");
+    bytes.extend(16u32.to_le_bytes());
+    bytes.extend(1u16.to_le_bytes());
+    bytes.extend(channels.to_le_bytes());
+    bytes.extend(sample_rate.to_le_bytes());
+    bytes.extend((sample_rate * channels as u32 * 2).to_le_bytes());
+    bytes.extend((channels * 2).to_le_bytes());
+    bytes.extend(16u16.to_le_bytes());
+    bytes.extend(b"data");
+    bytes.extend(data_len.to_le_bytes());
+    bytes.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+
+    bytes
+}
+
+/// Builds a 16-bit `.wav` of interleaved `samples`.
+pub fn wav(channels: u16, sample_rate: u32, samples: &[i16]) -> AudioSource {
+    AudioSource {
+        bytes: Arc::from(wav_bytes(channels, sample_rate, samples)),
+        format: AudioFormat::Wav,
+        metadata: AudioMetadata::default(),
+        loop_region: None,
+        gain: 1.,
+    }
+}
+
+/// Builds a stereo `.wav` of `frames` frames of silence.
+pub fn silent_wav(sample_rate: u32, frames: u32) -> AudioSource {
+    wav(2, sample_rate, &vec![0; frames as usize * 2])
+}
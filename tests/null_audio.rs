@@ -0,0 +1,220 @@
+//! Plays audio on the null backend, stepped by hand.
+
+mod common;
+
+use bevy::input::keyboard::{Key, KeyboardInput, NativeKey};
+use bevy::input::ButtonState;
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+
+use std::time::Duration;
+
+use rrpg::audio::{
+    AudioBundle, AudioControl, AudioPlugin, AudioSource, DecodedSound, NullAudio, NullClock,
+    PlaybackState,
+};
+use rrpg::rhythm::asset::Beatmap;
+use rrpg::rhythm::input::KeyboardInputPlugin;
+use rrpg::rhythm::{
+    BeatmapBundle, BeatmapInstance, ImageAssets, JudgementEvent, Rhythm, RhythmExt, RhythmPlugin,
+    RhythmSystem,
+};
+use rrpg::GameState;
+
+use common::silent_wav;
+
+const SAMPLE_RATE: u32 = 48_000;
+
+fn app() -> App {
+    let mut app = App::new();
+
+    app.add_plugins((MinimalPlugins, AssetPlugin::default()))
+        .insert_resource(NullAudio::new(SAMPLE_RATE, NullClock::Manual))
+        .add_plugins(AudioPlugin);
+
+    app
+}
+
+/// Steps the audio and the frame time by `duration`.
+fn step(app: &mut App, duration: Duration) {
+    app.world.resource_mut::<NullAudio>().step(duration);
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(duration));
+    app.update();
+}
+
+#[test]
+fn timestamp_advances_by_stepped_frames() {
+    let mut app = app();
+
+    let source = app
+        .world
+        .resource_mut::<Assets<AudioSource>>()
+        .add(silent_wav(SAMPLE_RATE, SAMPLE_RATE * 2));
+
+    let entity = app
+        .world
+        .spawn(AudioBundle {
+            source,
+            ..Default::default()
+        })
+        .id();
+
+    // start the audio; nothing is mixed until a step
+    app.update();
+
+    let actl = app.world.get::<AudioControl>(entity).unwrap().clone();
+    assert_eq!(actl.timestamp(), 0);
+
+    step(&mut app, Duration::from_millis(500));
+
+    assert_eq!(actl.state(), PlaybackState::Playing);
+    assert_eq!(actl.timestamp(), 24_000);
+    assert_eq!(actl.position(), Duration::from_millis(500));
+
+    step(&mut app, Duration::from_millis(250));
+
+    assert_eq!(actl.timestamp(), 36_000);
+    assert_eq!(actl.position(), Duration::from_millis(750));
+}
+
+#[test]
+fn nothing_plays_without_a_step() {
+    let mut app = app();
+
+    let source = app
+        .world
+        .resource_mut::<Assets<AudioSource>>()
+        .add(silent_wav(SAMPLE_RATE, SAMPLE_RATE));
+
+    let entity = app
+        .world
+        .spawn(AudioBundle {
+            source,
+            ..Default::default()
+        })
+        .id();
+
+    for _ in 0..4 {
+        app.update();
+    }
+
+    let actl = app.world.get::<AudioControl>(entity).unwrap();
+    assert_eq!(actl.timestamp(), 0);
+}
+
+#[test]
+fn decoded_sounds_are_resampled_to_the_device() {
+    let mut app = app();
+
+    // open the device first, so only the new sound is resampled
+    app.update();
+
+    let sound = DecodedSound {
+        samples: vec![0; 2 * 44_100].into(),
+        sample_rate: 44_100,
+        loop_region: None,
+        gain: 1.,
+    };
+    let sound = app.world.resource_mut::<Assets<DecodedSound>>().add(sound);
+
+    // the asset event is only sent at the end of the frame
+    for _ in 0..2 {
+        app.update();
+    }
+
+    let sounds = app.world.resource::<Assets<DecodedSound>>();
+    let sound = sounds.get(&sound).unwrap();
+
+    assert_eq!(sound.sample_rate, SAMPLE_RATE);
+    assert_eq!(sound.samples.len(), 2 * SAMPLE_RATE as usize);
+}
+
+/// The offsets of every judgement so far.
+#[derive(Default, Resource)]
+struct Judgements(Vec<Option<f32>>);
+
+fn record_judgements(mut events: EventReader<JudgementEvent>, mut judgements: ResMut<Judgements>) {
+    judgements.0.extend(events.read().map(|j| j.offset));
+}
+
+/// Presses or releases `key_code`.
+fn key(app: &mut App, key_code: KeyCode, state: ButtonState) {
+    app.world.send_event(KeyboardInput {
+        key_code,
+        logical_key: Key::Unidentified(NativeKey::Unidentified),
+        state,
+        window: Entity::PLACEHOLDER,
+    });
+}
+
+#[test]
+fn beatmap_plays_and_judges_headlessly() {
+    let mut app = app();
+
+    // everything but the sprites, which need a renderer to load
+    app.init_state::<GameState>()
+        .add_event::<KeyboardInput>()
+        .add_plugins((RhythmPlugin, KeyboardInputPlugin))
+        .init_resource::<Judgements>()
+        .add_systems(Update, record_judgements.after(RhythmSystem::Judgement))
+        .insert_resource(ImageAssets {
+            note_default: Handle::default(),
+            judgement_area: Handle::default(),
+            judgement_hit: Handle::default(),
+            judgement_hit_layout: Handle::default(),
+            lane_sheet: Handle::default(),
+            lane_sheet_layout: Handle::default(),
+        });
+
+    app.world
+        .resource_mut::<NextState<GameState>>()
+        .set(GameState::InBattle);
+
+    // one note on the first beat that is hit, one on the second that is not
+    let mut beatmap = ron::from_str::<Beatmap>(
+        r#"(
+            lane_count: 1,
+            song: (path: "song.wav", bpm: 60, offset: 0),
+            notes: [(beat: 1.0, lane: 0), (beat: 2.0, lane: 0)],
+        )"#,
+    )
+    .unwrap();
+
+    beatmap.song.handle = app
+        .world
+        .resource_mut::<Assets<AudioSource>>()
+        .add(silent_wav(SAMPLE_RATE, SAMPLE_RATE * 3));
+
+    let beatmap = app.world.resource_mut::<Assets<Beatmap>>().add(beatmap);
+    let entity = app.world.spawn(BeatmapBundle::new(beatmap)).id();
+
+    // enter the battle, spawn the beatmap, then start the song
+    for _ in 0..3 {
+        app.update();
+    }
+
+    assert!(app.world.get::<BeatmapInstance>(entity).is_some());
+
+    for _ in 0..19 {
+        step(&mut app, Duration::from_millis(50));
+    }
+
+    let position = app.world.resource::<Time<Rhythm>>().position();
+    assert!(position.abs_diff(Duration::from_millis(950)) < Duration::from_millis(5));
+
+    key(&mut app, KeyCode::KeyZ, ButtonState::Pressed);
+    step(&mut app, Duration::from_millis(50));
+    key(&mut app, KeyCode::KeyZ, ButtonState::Released);
+
+    // let the second note pass out of its window
+    for _ in 0..28 {
+        step(&mut app, Duration::from_millis(50));
+    }
+
+    let judgements = &app.world.resource::<Judgements>().0;
+    assert_eq!(judgements.len(), 2);
+
+    let hit = judgements[0].expect("the first note was missed");
+    assert!(hit.abs() <= 0.06, "hit {hit}s off");
+    assert_eq!(judgements[1], None);
+}
@@ -0,0 +1,106 @@
+//! Renders source chains offline, without an audio device.
+
+mod common;
+
+use std::io::Cursor;
+
+use rrpg::audio::source::{render_wav, Decoder, Resampler, Source};
+use rrpg::audio::{AudioBus, AudioControl, Mixer};
+
+use common::{wav, wav_bytes};
+
+const SAMPLE_RATE: u32 = 48_000;
+
+const SAMPLES: [i16; 8] = [0, 1, -1, 1000, -1000, 12345, i16::MAX, i16::MIN];
+
+/// The render of [`sawtooth`] resampled from 44.1kHz.
+///
+/// Run with `UPDATE_GOLDEN=1` to write this again after an intended change
+/// to the resampler.
+const RESAMPLED_GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/resampled.wav");
+
+/// A stereo sawtooth, rising on the left and falling on the right, a little
+/// longer than a resampler chunk.
+fn sawtooth() -> Vec<i16> {
+    (0..5000)
+        .flat_map(|i| {
+            let saw = (i * 300 % 20_000) as i16 - 10_000;
+            [saw, -saw / 2]
+        })
+        .collect()
+}
+
+/// Renders `source` to the bytes of a `.wav`.
+fn render<S>(source: &mut S, max_frames: Option<u64>) -> (u64, Vec<u8>)
+where
+    S: Source + ?Sized,
+    S::Error: std::fmt::Debug,
+{
+    let mut out = Cursor::new(Vec::new());
+    let frames = render_wav(source, max_frames, &mut out).unwrap();
+
+    (frames, out.into_inner())
+}
+
+#[test]
+fn renders_a_decoder_unchanged() {
+    let mut decoder = Decoder::new(wav(2, SAMPLE_RATE, &SAMPLES)).unwrap();
+
+    let (frames, bytes) = render(&mut decoder, None);
+
+    assert_eq!(frames, SAMPLES.len() as u64 / 2);
+    assert_eq!(bytes, wav_bytes(2, SAMPLE_RATE, &SAMPLES));
+}
+
+#[test]
+fn renders_a_resampler_like_the_golden_file() {
+    let decoder = Decoder::new(wav(2, 44_100, &sawtooth())).unwrap();
+    let mut resampler = Resampler::new(decoder, SAMPLE_RATE).unwrap();
+
+    let (frames, bytes) = render(&mut resampler, None);
+
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(RESAMPLED_GOLDEN, &bytes).unwrap();
+    }
+
+    // 5000 frames at 44.1kHz is 5442.2 frames at 48kHz
+    assert_eq!(frames, 5443);
+    assert!(bytes == std::fs::read(RESAMPLED_GOLDEN).unwrap());
+}
+
+#[test]
+fn renders_a_mix_of_voices() {
+    let mut mixer = Mixer::new(SAMPLE_RATE, 2);
+
+    let music = AudioControl::default();
+    music.set_volume(0.5);
+
+    let source = Decoder::new(wav(2, SAMPLE_RATE, &[8000, -4000].repeat(100)))
+        .unwrap()
+        .boxed();
+    mixer.add(music.controlled(source), music.clone(), AudioBus::Music);
+
+    // halves the left channel
+    let sfx = AudioControl::default();
+    sfx.set_pan(0.5);
+
+    let source = Decoder::new(wav(2, SAMPLE_RATE, &[1000, 3000].repeat(50)))
+        .unwrap()
+        .boxed();
+    mixer.add(sfx.controlled(source), sfx.clone(), AudioBus::Sfx);
+
+    // the mixer never ends, so it plays silence past the sources
+    let (frames, bytes) = render(&mut mixer, Some(150));
+
+    let expected = [4500, 1000]
+        .repeat(50)
+        .into_iter()
+        .chain([4000, -2000].repeat(50))
+        .chain([0, 0].repeat(50))
+        .collect::<Vec<_>>();
+
+    assert_eq!(frames, 150);
+    assert_eq!(music.timestamp(), 100);
+    assert_eq!(sfx.timestamp(), 50);
+    assert_eq!(bytes, wav_bytes(2, SAMPLE_RATE, &expected));
+}